@@ -1,7 +1,7 @@
 use bbqueue::{ArrayLength, AutoReleaseGrantR};
 use core::convert::TryInto;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum CoilState {
     On = 0xFF00,
     Off = 0x0000,
@@ -18,10 +18,10 @@ impl<'a, S: ArrayLength<u8>> CoilStore<'a, S> {
         CoilStore { data, count }
     }
 
-    pub fn iter(&'a self) -> CoilIterator<'a> {
+    pub fn iter(&self) -> CoilIterator<'_> {
         CoilIterator {
             current: 0,
-            data: &self.data[7..7 + (self.count + (8 - 1)) / 8],
+            data: &self.data[7..7 + self.count.div_ceil(8)],
             count: self.count,
         }
     }
@@ -29,11 +29,16 @@ impl<'a, S: ArrayLength<u8>> CoilStore<'a, S> {
     pub fn len(&self) -> usize {
         self.count
     }
+
+    /// Returns the number of bytes holding the coils according to the request.
+    pub fn byte_count(&self) -> usize {
+        self.data[6] as usize
+    }
 }
 
-impl<'a, S: ArrayLength<u8>> IntoIterator for &'a CoilStore<'a, S> {
+impl<'a, 'b, S: ArrayLength<u8>> IntoIterator for &'b CoilStore<'a, S> {
     type Item = CoilState;
-    type IntoIter = CoilIterator<'a>;
+    type IntoIter = CoilIterator<'b>;
 
     fn into_iter(self) -> Self::IntoIter {
         self.iter()
@@ -67,20 +72,117 @@ impl<'a> Iterator for CoilIterator<'a> {
 #[derive(Debug, PartialEq)]
 pub struct RegisterStore<'a, S: ArrayLength<u8>> {
     data: AutoReleaseGrantR<'a, S>,
+    offset: usize,
 }
 
 impl<'a, S: ArrayLength<u8>> RegisterStore<'a, S> {
-    pub fn new(data: AutoReleaseGrantR<'a, S>) -> RegisterStore<'a, S> {
-        RegisterStore { data }
+    /// Creates a store for the registers starting at `offset` in the frame.
+    ///
+    /// The byte count of the register values is expected right in front of them.
+    pub fn new(data: AutoReleaseGrantR<'a, S>, offset: usize) -> RegisterStore<'a, S> {
+        RegisterStore { data, offset }
     }
 
-    pub fn iter(&'a self) -> impl Iterator<Item = u16> + 'a {
-        self.data[7..self.data.len() - 2]
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        self.data[self.offset..self.offset + self.data[self.offset - 1] as usize]
             .chunks(2)
             .map(|s| u16::from_be_bytes(s.try_into().unwrap_or_default()))
     }
 
     pub fn len(&self) -> usize {
-        self.data.len() / 2
+        self.data[self.offset - 1] as usize / 2
+    }
+}
+
+/// The sub-requests of a read or write file record request.
+#[derive(Debug, PartialEq)]
+pub struct FileRecordStore<'a, S: ArrayLength<u8>> {
+    data: AutoReleaseGrantR<'a, S>,
+    write: bool,
+}
+
+impl<'a, S: ArrayLength<u8>> FileRecordStore<'a, S> {
+    pub fn new(data: AutoReleaseGrantR<'a, S>, write: bool) -> FileRecordStore<'a, S> {
+        FileRecordStore { data, write }
+    }
+
+    pub fn iter(&self) -> FileRecordIterator<'_> {
+        FileRecordIterator {
+            data: self.bytes(),
+            write: self.write,
+        }
+    }
+
+    /// Returns the sub-requests as they were received.
+    pub fn bytes(&self) -> &[u8] {
+        &self.data[3..3 + self.data[2] as usize]
+    }
+}
+
+/// A single sub-request of a read or write file record request.
+#[derive(Debug, PartialEq)]
+pub struct FileRecord<'b> {
+    pub reference_type: u8,
+    pub file_number: u16,
+    pub record_number: u16,
+    pub record_length: u16,
+    /// The record data to write in big endian byte order. It is empty for read requests.
+    pub data: &'b [u8],
+}
+
+pub struct FileRecordIterator<'b> {
+    data: &'b [u8],
+    write: bool,
+}
+
+impl<'b> Iterator for FileRecordIterator<'b> {
+    type Item = FileRecord<'b>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.len() < 7 {
+            return None;
+        }
+
+        let record_length = u16::from_be_bytes([self.data[5], self.data[6]]);
+        let data_len = if self.write {
+            2 * record_length as usize
+        } else {
+            0
+        };
+        // Stop at a truncated sub-request.
+        if self.data.len() < 7 + data_len {
+            return None;
+        }
+
+        let record = FileRecord {
+            reference_type: self.data[0],
+            file_number: u16::from_be_bytes([self.data[1], self.data[2]]),
+            record_number: u16::from_be_bytes([self.data[3], self.data[4]]),
+            record_length,
+            data: &self.data[7..7 + data_len],
+        };
+        self.data = &self.data[7 + data_len..];
+        Some(record)
+    }
+}
+
+/// The PDU of a request to a user-defined function.
+#[derive(Debug, PartialEq)]
+pub struct Pdu<'a, S: ArrayLength<u8>> {
+    data: AutoReleaseGrantR<'a, S>,
+    frame_len: usize,
+}
+
+impl<'a, S: ArrayLength<u8>> Pdu<'a, S> {
+    pub fn new(data: AutoReleaseGrantR<'a, S>, frame_len: usize) -> Pdu<'a, S> {
+        Pdu { data, frame_len }
+    }
+
+    pub fn function_code(&self) -> u8 {
+        self.data[1]
+    }
+
+    /// Returns the PDU starting with the function code, without slave id and CRC.
+    pub fn bytes(&self) -> &[u8] {
+        &self.data[1..self.frame_len - 2]
     }
 }
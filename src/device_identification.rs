@@ -0,0 +1,80 @@
+use crate::consts::MAX_PDU_LEN;
+
+/// The ids of the objects with a meaning defined by the modbus specification.
+pub mod object_id {
+    pub const VENDOR_NAME: u8 = 0x00;
+    pub const PRODUCT_CODE: u8 = 0x01;
+    pub const MAJOR_MINOR_REVISION: u8 = 0x02;
+    pub const VENDOR_URL: u8 = 0x03;
+    pub const PRODUCT_NAME: u8 = 0x04;
+    pub const MODEL_NAME: u8 = 0x05;
+    pub const USER_APPLICATION_NAME: u8 = 0x06;
+}
+
+/// A single object of the device identification.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DeviceObject<'a> {
+    pub id: u8,
+    pub value: &'a [u8],
+}
+
+impl DeviceObject<'_> {
+    /// The maximum length of a value, such that the object fits into a single response.
+    pub const MAX_LEN: usize = 244;
+}
+
+/// The objects a slave identifies itself with.
+///
+/// The objects have to be sorted by their id.
+/// Objects 0x00-0x02 form the mandatory basic category, objects 0x03-0x7F the regular category
+/// and objects 0x80-0xFF the extended category.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DeviceIdentification<'a> {
+    pub objects: &'a [DeviceObject<'a>],
+}
+
+impl<'a> DeviceIdentification<'a> {
+    /// Panics if the value of an object is longer than `DeviceObject::MAX_LEN`,
+    /// which fails to compile if the identification is a constant.
+    pub const fn new(objects: &'a [DeviceObject<'a>]) -> DeviceIdentification<'a> {
+        let mut i = 0;
+        while i < objects.len() {
+            if objects[i].value.len() > DeviceObject::MAX_LEN {
+                panic!("device object too long");
+            }
+            i += 1;
+        }
+        DeviceIdentification { objects }
+    }
+
+    /// Returns the conformity level reported to the master.
+    ///
+    /// It is the highest category any object belongs to, with individual access always supported.
+    pub fn conformity_level(&self) -> u8 {
+        let category = match self.objects.last() {
+            Some(object) if object.id >= 0x80 => 0x03,
+            Some(object) if object.id >= 0x03 => 0x02,
+            _ => 0x01,
+        };
+        0x80 | category
+    }
+
+    /// Returns the object with the given id.
+    pub fn object(&self, id: u8) -> Option<&DeviceObject<'a>> {
+        self.objects.iter().find(|object| object.id == id)
+    }
+}
+
+/// The identification a slave answers a report server id request with.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ServerId<'a> {
+    /// The device specific server id, at most `ServerId::MAX_LEN` bytes long.
+    pub id: &'a [u8],
+    /// The run indicator status.
+    pub running: bool,
+}
+
+impl ServerId<'_> {
+    /// The maximum length of the id, such that it fits into a response together with the run indicator status.
+    pub const MAX_LEN: usize = MAX_PDU_LEN - 3;
+}
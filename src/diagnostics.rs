@@ -0,0 +1,242 @@
+use crate::{exception::Exception, response::ResponseWriter};
+
+/// The sub-function codes of the diagnostics function 0x08.
+pub(crate) mod sub_function {
+    pub const RETURN_QUERY_DATA: u16 = 0x00;
+    pub const RESTART_COMMUNICATIONS_OPTION: u16 = 0x01;
+    pub const RETURN_DIAGNOSTIC_REGISTER: u16 = 0x02;
+    pub const CHANGE_ASCII_INPUT_DELIMITER: u16 = 0x03;
+    pub const FORCE_LISTEN_ONLY_MODE: u16 = 0x04;
+    pub const CLEAR_COUNTERS_AND_DIAGNOSTIC_REGISTER: u16 = 0x0A;
+    pub const RETURN_BUS_MESSAGE_COUNT: u16 = 0x0B;
+    pub const RETURN_BUS_COMMUNICATION_ERROR_COUNT: u16 = 0x0C;
+    pub const RETURN_BUS_EXCEPTION_ERROR_COUNT: u16 = 0x0D;
+    pub const RETURN_SERVER_MESSAGE_COUNT: u16 = 0x0E;
+    pub const RETURN_SERVER_NO_RESPONSE_COUNT: u16 = 0x0F;
+    pub const RETURN_SERVER_NAK_COUNT: u16 = 0x10;
+    pub const RETURN_SERVER_BUSY_COUNT: u16 = 0x11;
+    pub const RETURN_BUS_CHARACTER_OVERRUN_COUNT: u16 = 0x12;
+    pub const CLEAR_OVERRUN_COUNTER_AND_FLAG: u16 = 0x14;
+}
+
+/// The events stored in the comm event log.
+pub(crate) mod event {
+    /// A request was received. Combined with the receive bits below.
+    pub const RECEIVE: u8 = 0x80;
+    pub const RECEIVE_COMMUNICATION_ERROR: u8 = 0x02;
+    pub const RECEIVE_CHARACTER_OVERRUN: u8 = 0x10;
+    pub const RECEIVE_LISTEN_ONLY: u8 = 0x20;
+    pub const RECEIVE_BROADCAST: u8 = 0x40;
+    /// A response was sent. Combined with the send bits below.
+    pub const SEND: u8 = 0x40;
+    pub const SEND_READ_EXCEPTION: u8 = 0x01;
+    pub const SEND_SERVER_ABORT_EXCEPTION: u8 = 0x02;
+    pub const SEND_SERVER_BUSY_EXCEPTION: u8 = 0x04;
+    pub const SEND_SERVER_NAK_EXCEPTION: u8 = 0x08;
+    pub const ENTERED_LISTEN_ONLY: u8 = 0x04;
+    pub const COMMUNICATION_RESTART: u8 = 0x00;
+}
+
+/// The number of events kept in the comm event log.
+const EVENT_LOG_LEN: usize = 64;
+
+/// The diagnostic counters of a serial line slave.
+///
+/// All counters wrap around on overflow.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct Counters {
+    /// The number of messages detected on the bus.
+    pub bus_message: u16,
+    /// The number of CRC errors.
+    pub bus_communication_error: u16,
+    /// The number of exception responses sent.
+    pub server_exception: u16,
+    /// The number of messages addressed to this slave, including broadcasts.
+    pub server_message: u16,
+    /// The number of messages addressed to this slave which were not answered.
+    pub server_no_response: u16,
+    /// The number of Negative Acknowledge exception responses sent.
+    pub server_nak: u16,
+    /// The number of Server Device Busy exception responses sent.
+    pub server_busy: u16,
+    /// The number of messages which could not be received because of a character overrun.
+    pub bus_character_overrun: u16,
+}
+
+/// Increments a counter, wrapping around on overflow.
+pub(crate) fn count(counter: &mut u16) {
+    *counter = counter.wrapping_add(1);
+}
+
+/// The state of the serial line diagnostics.
+pub(crate) struct Diagnostics {
+    pub(crate) counters: Counters,
+    pub(crate) register: u16,
+    pub(crate) ascii_delimiter: u8,
+    pub(crate) listen_only: bool,
+    pub(crate) busy: bool,
+    /// The number of successfully completed requests.
+    event_counter: u16,
+    /// The comm event log as a ring buffer, `event_head` being the position of the next event.
+    events: [u8; EVENT_LOG_LEN],
+    event_head: usize,
+    event_len: usize,
+}
+
+impl Diagnostics {
+    pub(crate) fn new() -> Diagnostics {
+        Diagnostics {
+            counters: Counters::default(),
+            register: 0,
+            ascii_delimiter: b'\n',
+            listen_only: false,
+            busy: false,
+            event_counter: 0,
+            events: [0; EVENT_LOG_LEN],
+            event_head: 0,
+            event_len: 0,
+        }
+    }
+
+    /// Stores an event in the comm event log, dropping the oldest event if the log is full.
+    pub(crate) fn log(&mut self, event: u8) {
+        self.events[self.event_head] = event;
+        self.event_head = (self.event_head + 1) % EVENT_LOG_LEN;
+        self.event_len = core::cmp::min(self.event_len + 1, EVENT_LOG_LEN);
+    }
+
+    /// Stores a receive event with the given receive bits in the comm event log.
+    pub(crate) fn log_receive(&mut self, bits: u8) {
+        let listen_only = if self.listen_only {
+            event::RECEIVE_LISTEN_ONLY
+        } else {
+            0
+        };
+        self.log(event::RECEIVE | listen_only | bits);
+    }
+
+    /// Stores a send event for a response with the given exception code in the comm event log.
+    pub(crate) fn log_send(&mut self, exception: Option<u8>) {
+        let bits = match exception {
+            Some(0x01..=0x03) => event::SEND_READ_EXCEPTION,
+            Some(0x04) => event::SEND_SERVER_ABORT_EXCEPTION,
+            Some(0x05..=0x06) => event::SEND_SERVER_BUSY_EXCEPTION,
+            Some(0x07) => event::SEND_SERVER_NAK_EXCEPTION,
+            _ => 0,
+        };
+        self.log(event::SEND | bits);
+    }
+
+    /// Counts a successfully completed request.
+    pub(crate) fn count_event(&mut self) {
+        count(&mut self.event_counter);
+    }
+
+    pub(crate) fn enter_listen_only(&mut self) {
+        self.listen_only = true;
+        self.log(event::ENTERED_LISTEN_ONLY);
+    }
+
+    /// Restarts the communication, which resets all counters and ends the listen only mode.
+    fn restart(&mut self, clear_log: bool) {
+        self.counters = Counters::default();
+        self.event_counter = 0;
+        self.listen_only = false;
+        if clear_log {
+            self.event_len = 0;
+        }
+        self.log(event::COMMUNICATION_RESTART);
+    }
+
+    /// Returns the status word reported with the comm event counter and log.
+    fn status(&self) -> u16 {
+        if self.busy {
+            0xFFFF
+        } else {
+            0x0000
+        }
+    }
+
+    /// Writes the data of a get comm event counter response.
+    pub(crate) fn comm_event_counter(&self, response: &mut ResponseWriter) {
+        response.push_u16(self.status());
+        response.push_u16(self.event_counter);
+    }
+
+    /// Writes the data of a get comm event log response with the most recent event first.
+    pub(crate) fn comm_event_log(&self, response: &mut ResponseWriter) {
+        response.push(6 + self.event_len as u8);
+        response.push_u16(self.status());
+        response.push_u16(self.event_counter);
+        response.push_u16(self.counters.bus_message);
+        for i in 1..=self.event_len {
+            response.push(self.events[(self.event_head + EVENT_LOG_LEN - i) % EVENT_LOG_LEN]);
+        }
+    }
+
+    /// Handles a diagnostics sub-function received in listen only mode.
+    ///
+    /// A valid restart communications option ends the listen only mode, everything else is ignored.
+    /// Nothing is ever answered in listen only mode.
+    pub(crate) fn listen(&mut self, sub_function: u16, data: u16) {
+        if sub_function == sub_function::RESTART_COMMUNICATIONS_OPTION
+            && (data == 0x0000 || data == 0xFF00)
+        {
+            self.restart(data == 0xFF00);
+        }
+    }
+
+    /// Executes a diagnostics sub-function and writes the data of its response.
+    ///
+    /// Returns whether the sub-function has to be answered.
+    pub(crate) fn execute(
+        &mut self,
+        sub_function: u16,
+        data: u16,
+        response: &mut ResponseWriter,
+    ) -> Result<bool, Exception> {
+        use sub_function::*;
+
+        let value = match sub_function {
+            RESTART_COMMUNICATIONS_OPTION => {
+                if data != 0x0000 && data != 0xFF00 {
+                    return Err(Exception::IllegalDataValue);
+                }
+                self.restart(data == 0xFF00);
+                data
+            }
+            RETURN_DIAGNOSTIC_REGISTER => self.register,
+            CHANGE_ASCII_INPUT_DELIMITER => {
+                self.ascii_delimiter = (data >> 8) as u8;
+                data
+            }
+            FORCE_LISTEN_ONLY_MODE => {
+                self.enter_listen_only();
+                return Ok(false);
+            }
+            CLEAR_COUNTERS_AND_DIAGNOSTIC_REGISTER => {
+                self.counters = Counters::default();
+                self.event_counter = 0;
+                self.register = 0;
+                data
+            }
+            RETURN_BUS_MESSAGE_COUNT => self.counters.bus_message,
+            RETURN_BUS_COMMUNICATION_ERROR_COUNT => self.counters.bus_communication_error,
+            RETURN_BUS_EXCEPTION_ERROR_COUNT => self.counters.server_exception,
+            RETURN_SERVER_MESSAGE_COUNT => self.counters.server_message,
+            RETURN_SERVER_NO_RESPONSE_COUNT => self.counters.server_no_response,
+            RETURN_SERVER_NAK_COUNT => self.counters.server_nak,
+            RETURN_SERVER_BUSY_COUNT => self.counters.server_busy,
+            RETURN_BUS_CHARACTER_OVERRUN_COUNT => self.counters.bus_character_overrun,
+            CLEAR_OVERRUN_COUNTER_AND_FLAG => {
+                self.counters.bus_character_overrun = 0;
+                data
+            }
+            _ => return Err(Exception::IllegalFunction),
+        };
+
+        response.push_u16(sub_function);
+        response.push_u16(value);
+        Ok(true)
+    }
+}
@@ -0,0 +1,45 @@
+use crate::{consts::MAX_FRAME_LEN, error::Error};
+
+/// The echo of our own transmission a transceiver loops back into the receive path.
+pub(crate) struct Echo {
+    transmitted: [u8; MAX_FRAME_LEN],
+    len: usize,
+    /// The number of transmitted bytes already received back.
+    received: usize,
+}
+
+impl Echo {
+    pub(crate) fn new() -> Echo {
+        Echo {
+            transmitted: [0; MAX_FRAME_LEN],
+            len: 0,
+            received: 0,
+        }
+    }
+
+    /// Remembers transmitted data to be expected back after the echo still outstanding.
+    pub(crate) fn transmitted(&mut self, data: &[u8]) {
+        if self.received == self.len {
+            self.len = 0;
+            self.received = 0;
+        }
+        let len = core::cmp::min(data.len(), MAX_FRAME_LEN - self.len);
+        self.transmitted[self.len..self.len + len].copy_from_slice(&data[..len]);
+        self.len += len;
+    }
+
+    /// Strips the expected echo off the start of the received data and returns the data following it.
+    ///
+    /// If the received data differs from what was transmitted, another node transmitted at the same time.
+    /// The rest of the echo is dropped then, as the received data cannot be told apart anymore.
+    pub(crate) fn strip<'d>(&mut self, data: &'d [u8]) -> Result<&'d [u8], Error> {
+        let expected = &self.transmitted[self.received..self.len];
+        let len = core::cmp::min(expected.len(), data.len());
+        if data[..len] != expected[..len] {
+            self.received = self.len;
+            return Err(Error::BusCollision);
+        }
+        self.received += len;
+        Ok(&data[len..])
+    }
+}
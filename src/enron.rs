@@ -0,0 +1,33 @@
+use core::ops::RangeInclusive;
+
+/// The holding register ranges Enron Modbus devices commonly use for 32 bit values,
+/// 5000-5999 for long integers and 7000-7999 for floating point values.
+pub const ENRON_LONG_REGISTERS: [RangeInclusive<u16>; 2] = [5000..=5999, 7000..=7999];
+
+/// The register the event log is read from and acknowledged at.
+pub(crate) const EVENT_LOG_ADDRESS: u16 = 32;
+
+/// The configuration of the Enron Modbus extension.
+///
+/// Holding registers within the long register ranges hold a 32 bit value per address.
+/// They are read with four bytes per register and written with four bytes per value,
+/// the most significant half first.
+/// Besides that, reading holding register 32 returns the event log
+/// and writing coil 32 acknowledges the events returned.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Enron<'a> {
+    pub long_registers: &'a [RangeInclusive<u16>],
+}
+
+impl<'a> Enron<'a> {
+    pub const fn new(long_registers: &'a [RangeInclusive<u16>]) -> Enron<'a> {
+        Enron { long_registers }
+    }
+
+    /// Returns whether the register at `address` holds a 32 bit value.
+    pub fn is_long(&self, address: u16) -> bool {
+        self.long_registers
+            .iter()
+            .any(|range| range.contains(&address))
+    }
+}
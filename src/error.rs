@@ -2,4 +2,15 @@
 pub enum Error {
     Crc,
     UnknownFunction(u8),
+    /// The frame is malformed and cannot be interpreted.
+    InvalidFrame,
+    /// A response was received that does not belong to any outstanding request.
+    UnexpectedResponse,
+    /// The echo of our own transmission differs from what was sent,
+    /// so another node transmitted at the same time.
+    BusCollision,
+    /// The function code is not reserved for user-defined functions.
+    ReservedFunction(u8),
+    /// Responses were to be queued without a transmit buffer set.
+    NoTransmitBuffer,
 }
@@ -0,0 +1,17 @@
+/// An exception code a slave (or a gateway in its place) answers a request with.
+///
+/// The exception response mirrors the function code of the request with the MSB set,
+/// followed by one of these codes.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Exception {
+    IllegalFunction = 0x01,
+    IllegalDataAddress = 0x02,
+    IllegalDataValue = 0x03,
+    ServerDeviceFailure = 0x04,
+    Acknowledge = 0x05,
+    ServerDeviceBusy = 0x06,
+    NegativeAcknowledge = 0x07,
+    MemoryParityError = 0x08,
+    GatewayPathUnavailable = 0x0A,
+    GatewayTargetFailedToRespond = 0x0B,
+}
@@ -0,0 +1,415 @@
+use crate::{
+    exception::Exception,
+    general::{self, Crc16, SoftwareCrc},
+    handler::FileStore,
+};
+
+/// The number of image bytes held by a single file of 10000 records.
+const FILE_LEN: u32 = 2 * 10000;
+
+/// The records of the control file.
+mod record {
+    /// The image size in bytes as two registers, most significant register first.
+    pub const SIZE: u16 = 0;
+    /// The modbus CRC of the whole image.
+    pub const CRC: u16 = 2;
+    /// Takes one of the commands, reads as 0.
+    pub const COMMAND: u16 = 3;
+    /// The state of the transfer, read only.
+    pub const STATE: u16 = 4;
+    /// The number of image bytes received as two registers, read only.
+    pub const RECEIVED: u16 = 5;
+    /// The number of records in the control file.
+    pub const LEN: u16 = 7;
+}
+
+/// The commands written to the command record of the control file.
+pub mod firmware_command {
+    /// Verifies the received image and hands it over to the sink.
+    pub const FINISH: u16 = 1;
+    /// Discards the current transfer.
+    pub const ABORT: u16 = 2;
+}
+
+/// The state of a firmware transfer as reported in the control file.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TransferState {
+    Idle = 0,
+    Receiving = 1,
+    Complete = 2,
+    /// The received image did not match its CRC.
+    Failed = 3,
+}
+
+/// The storage a firmware image is written to, typically an inactive flash bank.
+pub trait ImageSink {
+    /// Prepares the storage for an image of `size` bytes, e.g. by erasing the bank.
+    fn begin(&mut self, size: u32) -> Result<(), Exception>;
+
+    /// Writes the next chunk of the image at `offset`.
+    ///
+    /// Chunks are written in order and without gaps.
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Exception>;
+
+    /// Called once the whole image was received and verified, e.g. to mark the bank as bootable.
+    fn finish(&mut self) -> Result<(), Exception>;
+}
+
+/// Receives a firmware image through the write file record function.
+///
+/// The transfer is controlled through the records of the control file:
+///
+/// | Record | Content                                    |
+/// |--------|--------------------------------------------|
+/// | 0-1    | image size in bytes                        |
+/// | 2      | CRC of the image                           |
+/// | 3      | command, see [`firmware_command`]          |
+/// | 4      | [`TransferState`], read only               |
+/// | 5-6    | number of bytes received so far, read only |
+///
+/// Writing the size and CRC in a single sub-request starts a transfer.
+/// The image is then written in chunks to the files following the control file,
+/// each file holding 20000 bytes of the image.
+/// Chunks have to be written in order. The last chunk may be written again, such that a master can simply
+/// repeat a chunk whose response got lost. It is acknowledged without being written twice, but only if its
+/// content is the same as before, which is checked through the CRC of the chunk.
+/// Each chunk is protected by the CRC of its frame, the whole image by the CRC given when starting the transfer.
+///
+/// An interrupted transfer is resumed by starting it again with the same size and CRC,
+/// which keeps the bytes received so far, and continuing with the chunk at the received offset.
+pub struct FirmwareUpdate<K: ImageSink> {
+    sink: K,
+    control_file: u16,
+    state: TransferState,
+    size: u32,
+    crc: u16,
+    received: u32,
+    /// The CRC of the bytes received so far.
+    running_crc: SoftwareCrc,
+    /// The offset, length and CRC of the last chunk written.
+    last_chunk: Option<(u32, usize, u16)>,
+}
+
+impl<K: ImageSink> FirmwareUpdate<K> {
+    /// Creates a firmware update controlled through `control_file`.
+    pub fn new(sink: K, control_file: u16) -> FirmwareUpdate<K> {
+        FirmwareUpdate {
+            sink,
+            control_file,
+            state: TransferState::Idle,
+            size: 0,
+            crc: 0,
+            received: 0,
+            running_crc: SoftwareCrc::new(),
+            last_chunk: None,
+        }
+    }
+
+    pub fn state(&self) -> TransferState {
+        self.state
+    }
+
+    /// Returns the number of image bytes received so far.
+    pub fn received(&self) -> u32 {
+        self.received
+    }
+
+    pub fn sink(&mut self) -> &mut K {
+        &mut self.sink
+    }
+
+    /// Starts a transfer or resumes it if the image is the same as the one being received.
+    fn start(&mut self, size: u32, crc: u16) -> Result<(), Exception> {
+        // The image has to fit into the files following the control file.
+        let files = (u16::MAX - self.control_file) as u32;
+        if size == 0 || size > files * FILE_LEN {
+            return Err(Exception::IllegalDataValue);
+        }
+        if self.state == TransferState::Receiving && size == self.size && crc == self.crc {
+            return Ok(());
+        }
+
+        self.state = TransferState::Idle;
+        self.sink.begin(size)?;
+        self.state = TransferState::Receiving;
+        self.size = size;
+        self.crc = crc;
+        self.received = 0;
+        self.running_crc.reset();
+        self.last_chunk = None;
+        Ok(())
+    }
+
+    fn execute(&mut self, command: u16) -> Result<(), Exception> {
+        match command {
+            firmware_command::FINISH => {
+                if self.state != TransferState::Receiving || self.received != self.size {
+                    return Err(Exception::IllegalDataValue);
+                }
+                if self.running_crc.get() != self.crc {
+                    self.state = TransferState::Failed;
+                    return Err(Exception::IllegalDataValue);
+                }
+                self.sink.finish()?;
+                self.state = TransferState::Complete;
+                Ok(())
+            }
+            firmware_command::ABORT => {
+                self.state = TransferState::Idle;
+                Ok(())
+            }
+            _ => Err(Exception::IllegalDataValue),
+        }
+    }
+
+    /// Writes a chunk of the image starting at `offset`.
+    fn write_chunk(&mut self, offset: u32, data: &[u8]) -> Result<(), Exception> {
+        if self.state != TransferState::Receiving {
+            return Err(Exception::IllegalDataValue);
+        }
+
+        // The last record may hold a padding byte for images of an odd size.
+        let end = offset + data.len() as u32;
+        if end > self.size + self.size % 2 {
+            return Err(Exception::IllegalDataAddress);
+        }
+        if offset > self.received {
+            return Err(Exception::IllegalDataAddress);
+        }
+        if offset < self.received {
+            // Only the last chunk may be repeated, possibly extended.
+            match self.last_chunk {
+                Some((last_offset, last_len, crc))
+                    if offset == last_offset && data.len() >= last_len =>
+                {
+                    if general::crc(&data[..last_len]) != crc {
+                        return Err(Exception::IllegalDataValue);
+                    }
+                }
+                _ => return Err(Exception::IllegalDataAddress),
+            }
+            if end <= self.received {
+                // The chunk was already received.
+                return Ok(());
+            }
+        }
+
+        let start = (self.received - offset) as usize;
+        let len = (core::cmp::min(end, self.size) - self.received) as usize;
+        let chunk = &data[start..start + len];
+        self.sink.write(self.received, chunk)?;
+        self.running_crc.update(chunk);
+        self.received += len as u32;
+        self.last_chunk = Some((offset, data.len(), general::crc(data)));
+        Ok(())
+    }
+
+    /// Returns the value of a record of the control file.
+    fn control_record(&self, record_number: u16) -> u16 {
+        match record_number {
+            record::SIZE => (self.size >> 16) as u16,
+            r if r == record::SIZE + 1 => self.size as u16,
+            record::CRC => self.crc,
+            record::STATE => self.state as u16,
+            record::RECEIVED => (self.received >> 16) as u16,
+            r if r == record::RECEIVED + 1 => self.received as u16,
+            _ => 0,
+        }
+    }
+}
+
+impl<K: ImageSink> FileStore for FirmwareUpdate<K> {
+    fn read_records(
+        &mut self,
+        file_number: u16,
+        record_number: u16,
+        data: &mut [u8],
+    ) -> Result<(), Exception> {
+        if file_number != self.control_file
+            || record_number as usize + data.len() / 2 > record::LEN as usize
+        {
+            return Err(Exception::IllegalDataAddress);
+        }
+        for (i, chunk) in data.chunks_mut(2).enumerate() {
+            chunk.copy_from_slice(&self.control_record(record_number + i as u16).to_be_bytes());
+        }
+        Ok(())
+    }
+
+    fn write_records(
+        &mut self,
+        file_number: u16,
+        record_number: u16,
+        data: &[u8],
+    ) -> Result<(), Exception> {
+        if file_number > self.control_file {
+            let file = (file_number - self.control_file - 1) as u32;
+            return self.write_chunk(file * FILE_LEN + 2 * record_number as u32, data);
+        }
+        if file_number != self.control_file {
+            return Err(Exception::IllegalDataAddress);
+        }
+
+        match (record_number, data.len()) {
+            (record::SIZE, 6) => self.start(
+                u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+                u16::from_be_bytes([data[4], data[5]]),
+            ),
+            (record::COMMAND, 2) => self.execute(u16::from_be_bytes([data[0], data[1]])),
+            _ => Err(Exception::IllegalDataAddress),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{firmware_command, FirmwareUpdate, ImageSink, TransferState};
+    use crate::{Exception, FileStore};
+
+    const CONTROL_FILE: u16 = 0x10;
+
+    /// An in-memory flash bank.
+    #[derive(Default)]
+    struct Bank {
+        image: Vec<u8>,
+        bootable: bool,
+    }
+
+    impl ImageSink for Bank {
+        fn begin(&mut self, _size: u32) -> Result<(), Exception> {
+            self.image.clear();
+            self.bootable = false;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Exception> {
+            assert_eq!(offset as usize, self.image.len());
+            self.image.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), Exception> {
+            self.bootable = true;
+            Ok(())
+        }
+    }
+
+    fn image() -> Vec<u8> {
+        (0..45001).map(|i| (i * 7) as u8).collect()
+    }
+
+    fn start(update: &mut FirmwareUpdate<Bank>, image: &[u8]) -> Result<(), Exception> {
+        let crc = crate::general::crc(image);
+        let mut header = (image.len() as u32).to_be_bytes().to_vec();
+        header.extend_from_slice(&crc.to_be_bytes());
+        update.write_records(CONTROL_FILE, 0, &header)
+    }
+
+    /// Writes the chunk of the image at `offset` the way a master would, padded to whole records.
+    fn write(
+        update: &mut FirmwareUpdate<Bank>,
+        image: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<(), Exception> {
+        let mut padded = image.to_vec();
+        padded.push(0);
+        let end = core::cmp::min(offset + len, image.len() + image.len() % 2);
+        let chunk = &padded[offset..end];
+        let file = CONTROL_FILE + 1 + (offset / 20000) as u16;
+        let record = ((offset % 20000) / 2) as u16;
+        update.write_records(file, record, chunk)
+    }
+
+    fn finish(update: &mut FirmwareUpdate<Bank>) -> Result<(), Exception> {
+        update.write_records(CONTROL_FILE, 3, &firmware_command::FINISH.to_be_bytes())
+    }
+
+    #[test]
+    fn transfer() {
+        let image = image();
+        let mut update = FirmwareUpdate::new(Bank::default(), CONTROL_FILE);
+
+        start(&mut update, &image).unwrap();
+        for offset in (0..image.len()).step_by(200) {
+            write(&mut update, &image, offset, 200).unwrap();
+        }
+        finish(&mut update).unwrap();
+
+        assert_eq!(update.state(), TransferState::Complete);
+        assert_eq!(update.sink().image, image);
+        assert!(update.sink().bootable);
+
+        let mut status = [0; 6];
+        update.read_records(CONTROL_FILE, 4, &mut status).unwrap();
+        assert_eq!(status, [0x00, 0x02, 0x00, 0x00, 0xAF, 0xC9]);
+    }
+
+    #[test]
+    fn resume() {
+        let image = image();
+        let mut update = FirmwareUpdate::new(Bank::default(), CONTROL_FILE);
+
+        start(&mut update, &image).unwrap();
+        write(&mut update, &image, 0, 200).unwrap();
+        write(&mut update, &image, 200, 200).unwrap();
+        // A gap is rejected.
+        assert_eq!(
+            write(&mut update, &image, 600, 200),
+            Err(Exception::IllegalDataAddress)
+        );
+        // A repeated chunk is acknowledged without writing it twice.
+        write(&mut update, &image, 200, 200).unwrap();
+        // Unless its content differs.
+        let mut corrupted = image.clone();
+        corrupted[300] ^= 0x01;
+        assert_eq!(
+            write(&mut update, &corrupted, 200, 200),
+            Err(Exception::IllegalDataValue)
+        );
+        // Earlier chunks cannot be repeated.
+        assert_eq!(
+            write(&mut update, &image, 0, 200),
+            Err(Exception::IllegalDataAddress)
+        );
+
+        // Starting the same image again keeps the received bytes.
+        start(&mut update, &image).unwrap();
+        let mut received = [0; 4];
+        update.read_records(CONTROL_FILE, 5, &mut received).unwrap();
+        assert_eq!(received, [0x00, 0x00, 0x01, 0x90]);
+
+        for offset in (400..image.len()).step_by(280) {
+            write(&mut update, &image, offset, 280).unwrap();
+        }
+        finish(&mut update).unwrap();
+        assert_eq!(update.sink().image, image);
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let image = image();
+        let mut update = FirmwareUpdate::new(Bank::default(), CONTROL_FILE);
+
+        // An image larger than the files following the control file is rejected.
+        let mut header = u32::MAX.to_be_bytes().to_vec();
+        header.extend_from_slice(&[0x12, 0x34]);
+        assert_eq!(
+            update.write_records(CONTROL_FILE, 0, &header),
+            Err(Exception::IllegalDataValue)
+        );
+
+        start(&mut update, &image).unwrap();
+        // Finishing an incomplete image fails.
+        assert_eq!(finish(&mut update), Err(Exception::IllegalDataValue));
+
+        let mut corrupted = image.clone();
+        corrupted[1000] ^= 0x01;
+        for offset in (0..image.len()).step_by(200) {
+            write(&mut update, &corrupted, offset, 200).unwrap();
+        }
+        assert_eq!(finish(&mut update), Err(Exception::IllegalDataValue));
+        assert_eq!(update.state(), TransferState::Failed);
+        assert!(!update.sink().bootable);
+    }
+}
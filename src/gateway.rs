@@ -0,0 +1,381 @@
+#[cfg(feature = "atomic")]
+use bbqueue::atomic::BBBuffer;
+#[cfg(not(feature = "atomic"))]
+use bbqueue::cm_mutex::BBBuffer;
+
+use crate::{consts::MAX_PDU_LEN, error::Error, exception::Exception, general};
+use bbqueue::{ArrayLength, Consumer, Producer};
+
+/// The length of the MBAP header including the unit id.
+const MBAP_LEN: usize = 7;
+/// The length of the header a queued request is prefixed with.
+/// It holds the PDU length, the transaction id, the unit id and the slave id.
+const QUEUE_HEADER_LEN: usize = 5;
+
+/// Maps a modbus TCP unit id to the address of a slave on the serial line.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Route {
+    pub unit_id: u8,
+    pub slave_id: u8,
+}
+
+/// A request which was put on the serial line and awaits its response.
+struct Pending {
+    transaction_id: u16,
+    unit_id: u8,
+    slave_id: u8,
+    function: u8,
+}
+
+/// A modbus TCP to RTU gateway.
+///
+/// The gateway does not do any IO by itself.
+/// Requests received over TCP are handed to `on_tcp_request` which queues them.
+/// `poll_transmit` yields the next RTU frame to put on the serial line as soon as the line is free,
+/// and `on_serial_response` or `on_timeout` complete the outstanding request with a TCP response.
+/// This guarantees that there is never more than one request on the serial line.
+pub struct Gateway<'a, S: ArrayLength<u8>> {
+    routes: &'a [Route],
+    producer: Producer<'a, S>,
+    consumer: Consumer<'a, S>,
+    pending: Option<Pending>,
+}
+
+impl<'a, S: ArrayLength<u8> + 'a> Gateway<'a, S> {
+    pub fn new(bb: &'a BBBuffer<S>, routes: &'a [Route]) -> Gateway<'a, S> {
+        let (producer, consumer) = bb.try_split().unwrap_or_else(|_| panic!());
+
+        Gateway {
+            routes,
+            producer,
+            consumer,
+            pending: None,
+        }
+    }
+
+    /// Call this with a complete modbus TCP ADU received from a client.
+    ///
+    /// If the request can be answered right away, the TCP response is written to `response`
+    /// and its length is returned. This is the case if there is no route for the unit id
+    /// or if the queue in front of the serial line is full.
+    /// Otherwise the request is queued and `None` is returned.
+    pub fn on_tcp_request(
+        &mut self,
+        adu: &[u8],
+        response: &mut [u8],
+    ) -> Result<Option<usize>, Error> {
+        if adu.len() <= MBAP_LEN {
+            return Err(Error::InvalidFrame);
+        }
+
+        let transaction_id = u16::from_be_bytes([adu[0], adu[1]]);
+        let protocol_id = u16::from_be_bytes([adu[2], adu[3]]);
+        // The length field counts the unit id as well as the PDU.
+        let length = u16::from_be_bytes([adu[4], adu[5]]) as usize;
+        let unit_id = adu[6];
+        let pdu = &adu[MBAP_LEN..];
+
+        if protocol_id != 0 || length != pdu.len() + 1 || pdu.len() > MAX_PDU_LEN {
+            return Err(Error::InvalidFrame);
+        }
+
+        let slave_id = match self.routes.iter().find(|route| route.unit_id == unit_id) {
+            Some(route) => route.slave_id,
+            None => {
+                return Ok(Some(Self::write_exception(
+                    response,
+                    transaction_id,
+                    unit_id,
+                    pdu[0],
+                    Exception::GatewayPathUnavailable,
+                )))
+            }
+        };
+
+        // Store the request together with everything we need to forward it and to route back its response.
+        let mut wgr = match self.producer.grant_exact(QUEUE_HEADER_LEN + pdu.len()) {
+            Ok(wgr) => wgr,
+            Err(_) => {
+                return Ok(Some(Self::write_exception(
+                    response,
+                    transaction_id,
+                    unit_id,
+                    pdu[0],
+                    Exception::ServerDeviceBusy,
+                )))
+            }
+        };
+        wgr[0] = pdu.len() as u8;
+        wgr[1..3].copy_from_slice(&transaction_id.to_be_bytes());
+        wgr[3] = unit_id;
+        wgr[4] = slave_id;
+        wgr[QUEUE_HEADER_LEN..].copy_from_slice(pdu);
+        wgr.commit(QUEUE_HEADER_LEN + pdu.len());
+
+        Ok(None)
+    }
+
+    /// Writes the next RTU frame to send over the serial line into `frame` and returns its length.
+    ///
+    /// Returns `None` if a request is still awaiting its response or if there is no queued request.
+    /// The response timeout should be started as soon as the frame was sent.
+    /// Broadcasts do not get a response, so the serial line is free again right away.
+    pub fn poll_transmit(&mut self, frame: &mut [u8]) -> Option<usize> {
+        if self.pending.is_some() {
+            return None;
+        }
+
+        let rgr = self.consumer.read().ok()?;
+        let pdu_len = rgr[0] as usize;
+        let record_len = QUEUE_HEADER_LEN + pdu_len;
+        let slave_id = rgr[4];
+
+        frame[0] = slave_id;
+        frame[1..1 + pdu_len].copy_from_slice(&rgr[QUEUE_HEADER_LEN..record_len]);
+        let crc = general::crc(&frame[..1 + pdu_len]);
+        frame[1 + pdu_len..3 + pdu_len].copy_from_slice(&crc.to_le_bytes());
+
+        if slave_id != 0 {
+            self.pending = Some(Pending {
+                transaction_id: u16::from_be_bytes([rgr[1], rgr[2]]),
+                unit_id: rgr[3],
+                slave_id,
+                function: rgr[QUEUE_HEADER_LEN],
+            });
+        }
+
+        rgr.release(record_len);
+        Some(3 + pdu_len)
+    }
+
+    /// Call this with a complete RTU frame received from the serial line.
+    ///
+    /// On success the TCP response to the outstanding request is written to `response` and its length is returned.
+    /// Frames with an invalid CRC or from another slave are rejected
+    /// and the outstanding request keeps waiting for its response.
+    pub fn on_serial_response(
+        &mut self,
+        frame: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, Error> {
+        let pending = self.pending.as_ref().ok_or(Error::UnexpectedResponse)?;
+
+        if frame.len() < 4 {
+            return Err(Error::InvalidFrame);
+        }
+        if !general::crc_valid(frame) {
+            return Err(Error::Crc);
+        }
+        if frame[0] != pending.slave_id {
+            return Err(Error::UnexpectedResponse);
+        }
+
+        let len = Self::write_response(
+            response,
+            pending.transaction_id,
+            pending.unit_id,
+            &frame[1..frame.len() - 2],
+        );
+        self.pending = None;
+        Ok(len)
+    }
+
+    /// Call this if the outstanding request was not answered within the response timeout.
+    ///
+    /// Writes a Gateway Target Device Failed To Respond exception to `response` and returns its length.
+    /// The serial line is free for the next request afterwards.
+    pub fn on_timeout(&mut self, response: &mut [u8]) -> Option<usize> {
+        let pending = self.pending.take()?;
+        Some(Self::write_exception(
+            response,
+            pending.transaction_id,
+            pending.unit_id,
+            pending.function,
+            Exception::GatewayTargetFailedToRespond,
+        ))
+    }
+
+    fn write_response(response: &mut [u8], transaction_id: u16, unit_id: u8, pdu: &[u8]) -> usize {
+        response[0..2].copy_from_slice(&transaction_id.to_be_bytes());
+        response[2..4].copy_from_slice(&0u16.to_be_bytes());
+        response[4..6].copy_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+        response[6] = unit_id;
+        response[MBAP_LEN..MBAP_LEN + pdu.len()].copy_from_slice(pdu);
+        MBAP_LEN + pdu.len()
+    }
+
+    fn write_exception(
+        response: &mut [u8],
+        transaction_id: u16,
+        unit_id: u8,
+        function: u8,
+        exception: Exception,
+    ) -> usize {
+        Self::write_response(
+            response,
+            transaction_id,
+            unit_id,
+            &[function | 0x80, exception as u8],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Gateway, Route};
+    use crate::{general, Error};
+    use bbqueue::{atomic::consts::U2048, BBBuffer};
+
+    const ROUTES: [Route; 2] = [
+        Route {
+            unit_id: 1,
+            slave_id: 0x11,
+        },
+        Route {
+            unit_id: 2,
+            slave_id: 0x12,
+        },
+    ];
+
+    /// An in-memory serial line with a single slave attached.
+    /// The slave answers read holding registers requests with the register addresses as values.
+    struct SerialLink {
+        slave_id: u8,
+    }
+
+    impl SerialLink {
+        fn transfer(&self, frame: &[u8]) -> Option<Vec<u8>> {
+            if frame[0] != self.slave_id || !general::crc_valid(frame) {
+                return None;
+            }
+
+            let address = u16::from_be_bytes([frame[2], frame[3]]);
+            let count = u16::from_be_bytes([frame[4], frame[5]]);
+            let mut response = vec![frame[0], frame[1], count as u8 * 2];
+            for register in address..address + count {
+                response.extend_from_slice(&register.to_be_bytes());
+            }
+            let crc = general::crc(&response);
+            response.extend_from_slice(&crc.to_le_bytes());
+            Some(response)
+        }
+    }
+
+    fn read_request(transaction_id: u8, unit_id: u8) -> [u8; 12] {
+        [
+            0x00,
+            transaction_id,
+            0x00,
+            0x00,
+            0x00,
+            0x06,
+            unit_id,
+            0x03,
+            0x00,
+            0x6B,
+            0x00,
+            0x02,
+        ]
+    }
+
+    #[test]
+    fn request_is_forwarded_and_answered() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut gateway = Gateway::new(&bb, &ROUTES);
+        let link = SerialLink { slave_id: 0x11 };
+        let mut frame = [0; 256];
+        let mut response = [0; 260];
+
+        assert_eq!(
+            gateway.on_tcp_request(&read_request(0x2A, 1), &mut response),
+            Ok(None)
+        );
+
+        let len = gateway.poll_transmit(&mut frame).unwrap();
+        assert_eq!(
+            &frame[..len],
+            &[0x11, 0x03, 0x00, 0x6B, 0x00, 0x02, 0xB7, 0x47]
+        );
+
+        let answer = link.transfer(&frame[..len]).unwrap();
+        let len = gateway.on_serial_response(&answer, &mut response).unwrap();
+        assert_eq!(
+            &response[..len],
+            &[0x00, 0x2A, 0x00, 0x00, 0x00, 0x07, 0x01, 0x03, 0x04, 0x00, 0x6B, 0x00, 0x6C]
+        );
+    }
+
+    #[test]
+    fn unknown_unit_is_path_unavailable() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut gateway = Gateway::new(&bb, &ROUTES);
+        let mut frame = [0; 256];
+        let mut response = [0; 260];
+
+        let len = gateway
+            .on_tcp_request(&read_request(0x2A, 3), &mut response)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            &response[..len],
+            &[0x00, 0x2A, 0x00, 0x00, 0x00, 0x03, 0x03, 0x83, 0x0A]
+        );
+        assert_eq!(gateway.poll_transmit(&mut frame), None);
+    }
+
+    #[test]
+    fn silent_slave_is_target_failed_to_respond() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut gateway = Gateway::new(&bb, &ROUTES);
+        let link = SerialLink { slave_id: 0x11 };
+        let mut frame = [0; 256];
+        let mut response = [0; 260];
+
+        gateway
+            .on_tcp_request(&read_request(0x2A, 2), &mut response)
+            .unwrap();
+        let frame_len = gateway.poll_transmit(&mut frame).unwrap();
+        assert_eq!(link.transfer(&frame[..frame_len]), None);
+
+        let len = gateway.on_timeout(&mut response).unwrap();
+        assert_eq!(
+            &response[..len],
+            &[0x00, 0x2A, 0x00, 0x00, 0x00, 0x03, 0x02, 0x83, 0x0B]
+        );
+        // A late response must not be mistaken for the response to a later request.
+        assert_eq!(
+            gateway.on_serial_response(&frame[..frame_len], &mut response),
+            Err(Error::UnexpectedResponse)
+        );
+    }
+
+    #[test]
+    fn requests_are_serialized() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut gateway = Gateway::new(&bb, &ROUTES);
+        let link = SerialLink { slave_id: 0x11 };
+        let mut frame = [0; 256];
+        let mut response = [0; 260];
+
+        gateway
+            .on_tcp_request(&read_request(0x01, 1), &mut response)
+            .unwrap();
+        gateway
+            .on_tcp_request(&read_request(0x02, 1), &mut response)
+            .unwrap();
+
+        // The second request has to wait until the first one is answered.
+        let len = gateway.poll_transmit(&mut frame).unwrap();
+        assert_eq!(gateway.poll_transmit(&mut [0; 256]), None);
+
+        let answer = link.transfer(&frame[..len]).unwrap();
+        gateway.on_serial_response(&answer, &mut response).unwrap();
+        assert_eq!(&response[..2], &[0x00, 0x01]);
+
+        let len = gateway.poll_transmit(&mut frame).unwrap();
+        let answer = link.transfer(&frame[..len]).unwrap();
+        gateway.on_serial_response(&answer, &mut response).unwrap();
+        assert_eq!(&response[..2], &[0x00, 0x02]);
+        assert_eq!(gateway.poll_transmit(&mut frame), None);
+    }
+}
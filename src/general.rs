@@ -1,6 +1,194 @@
+use core::marker::PhantomData;
+
+/// The reflected polynomial of the CRC-16/MODBUS.
+const POLYNOMIAL: u16 = 0xA001;
+
 /// Returns true if the CRC matches the data.
 ///
 /// Expects the last two bytes of the data to be the CRC.
 pub fn crc_valid(data: &[u8]) -> bool {
-    crc16::State::<crc16::MODBUS>::calculate(data) == 0
+    DefaultCrc::update(0xFFFF, data) == 0
+}
+
+/// Calculates the CRC of the given data.
+///
+/// The CRC has to be appended to a frame in little endian byte order.
+pub fn crc(data: &[u8]) -> u16 {
+    DefaultCrc::update(0xFFFF, data)
+}
+
+/// A software implementation of the CRC-16/MODBUS.
+pub trait Crc {
+    /// Continues the computation of `crc` with `data` and returns the new CRC.
+    fn update(crc: u16, data: &[u8]) -> u16;
+}
+
+/// Computes the CRC a byte at a time with a table of 512 bytes.
+pub struct TableCrc;
+
+/// Computes the CRC a nibble at a time with a table of 32 bytes.
+pub struct NibbleCrc;
+
+/// Computes the CRC a bit at a time without a table.
+pub struct BitwiseCrc;
+
+/// The implementation chosen with the `crc-nibble` and `crc-bitwise` features, `TableCrc` without either.
+#[cfg(feature = "crc-bitwise")]
+pub type DefaultCrc = BitwiseCrc;
+#[cfg(all(feature = "crc-nibble", not(feature = "crc-bitwise")))]
+pub type DefaultCrc = NibbleCrc;
+#[cfg(not(any(feature = "crc-nibble", feature = "crc-bitwise")))]
+pub type DefaultCrc = TableCrc;
+
+/// Shifts `bits` bits out of `crc`.
+const fn shift(mut crc: u16, bits: u32) -> u16 {
+    let mut i = 0;
+    while i < bits {
+        crc = if crc & 1 != 0 {
+            (crc >> 1) ^ POLYNOMIAL
+        } else {
+            crc >> 1
+        };
+        i += 1;
+    }
+    crc
+}
+
+const fn table<const N: usize>(bits: u32) -> [u16; N] {
+    let mut table = [0; N];
+    let mut i = 0;
+    while i < N {
+        table[i] = shift(i as u16, bits);
+        i += 1;
+    }
+    table
+}
+
+static BYTE_TABLE: [u16; 256] = table(8);
+static NIBBLE_TABLE: [u16; 16] = table(4);
+
+impl Crc for TableCrc {
+    fn update(mut crc: u16, data: &[u8]) -> u16 {
+        for byte in data {
+            crc = (crc >> 8) ^ BYTE_TABLE[((crc ^ *byte as u16) & 0xFF) as usize];
+        }
+        crc
+    }
+}
+
+impl Crc for NibbleCrc {
+    fn update(mut crc: u16, data: &[u8]) -> u16 {
+        for byte in data {
+            crc ^= *byte as u16;
+            crc = (crc >> 4) ^ NIBBLE_TABLE[(crc & 0x0F) as usize];
+            crc = (crc >> 4) ^ NIBBLE_TABLE[(crc & 0x0F) as usize];
+        }
+        crc
+    }
+}
+
+impl Crc for BitwiseCrc {
+    fn update(mut crc: u16, data: &[u8]) -> u16 {
+        for byte in data {
+            crc = shift(crc ^ *byte as u16, 8);
+        }
+        crc
+    }
+}
+
+/// The state of a CRC-16/MODBUS computation, e.g. held by a hardware CRC unit.
+///
+/// The receiver updates the CRC of a frame as its bytes arrive, such that it is ready once the frame is complete.
+/// A single computation runs at a time though: the bytes of further frames received in the same chunk
+/// are fed in only once the frames before them were taken, so completing those takes time linear in their length.
+pub trait Crc16 {
+    /// Restarts the computation with the initial value 0xFFFF.
+    fn reset(&mut self);
+
+    /// Continues the computation with `data`.
+    fn update(&mut self, data: &[u8]);
+
+    /// Returns the CRC of the data since the last reset.
+    fn get(&self) -> u16;
+}
+
+/// The state of a CRC-16/MODBUS computed in software by `C`.
+pub struct SoftwareCrc<C: Crc = DefaultCrc> {
+    crc: u16,
+    implementation: PhantomData<C>,
+}
+
+impl<C: Crc> SoftwareCrc<C> {
+    pub fn new() -> SoftwareCrc<C> {
+        SoftwareCrc {
+            crc: 0xFFFF,
+            implementation: PhantomData,
+        }
+    }
+}
+
+impl<C: Crc> Default for SoftwareCrc<C> {
+    fn default() -> SoftwareCrc<C> {
+        SoftwareCrc::new()
+    }
+}
+
+impl<C: Crc> Crc16 for SoftwareCrc<C> {
+    fn reset(&mut self) {
+        self.crc = 0xFFFF;
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.crc = C::update(self.crc, data);
+    }
+
+    fn get(&self) -> u16 {
+        self.crc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BitwiseCrc, Crc, Crc16, NibbleCrc, SoftwareCrc, TableCrc};
+
+    /// Frames of the receiver tests, each ending with its CRC.
+    const FRAMES: [&[u8]; 5] = [
+        &[0x11, 0x01, 0x00, 0x13, 0x00, 0x25, 0x0E, 0x84],
+        &[0x11, 0x03, 0x00, 0x01, 0x00, 0x01, 0xD7, 0x5A],
+        &[0x11, 0x03, 0x02, 0x00, 0x2A, 0xF8, 0x58],
+        &[0x11, 0x06, 0x00, 0x01, 0x00, 0x03, 0x9A, 0x9B],
+        &[0x00, 0x06, 0x00, 0x01, 0x00, 0x03, 0x99, 0xDA],
+    ];
+
+    fn verify<C: Crc>() {
+        // The check value of the CRC-16/MODBUS.
+        assert_eq!(C::update(0xFFFF, b"123456789"), 0x4B37);
+
+        for frame in FRAMES.iter() {
+            let (data, crc) = frame.split_at(frame.len() - 2);
+            assert_eq!(C::update(0xFFFF, data).to_le_bytes(), crc);
+            assert_eq!(C::update(0xFFFF, frame), 0);
+
+            // Computing the CRC in pieces gives the same result.
+            let mut state = SoftwareCrc::<C>::new();
+            state.update(&frame[..3]);
+            state.update(&frame[3..]);
+            assert_eq!(state.get(), 0);
+        }
+    }
+
+    #[test]
+    fn table() {
+        verify::<TableCrc>();
+    }
+
+    #[test]
+    fn nibble() {
+        verify::<NibbleCrc>();
+    }
+
+    #[test]
+    fn bitwise() {
+        verify::<BitwiseCrc>();
+    }
 }
@@ -0,0 +1,145 @@
+use crate::{
+    data::CoilState,
+    device_identification::{DeviceIdentification, ServerId},
+    exception::Exception,
+};
+
+/// The data model of a modbus slave.
+///
+/// Every function answers with an Illegal Function exception by default,
+/// so only the functions supported by the application have to be implemented.
+/// Returning an exception from any function aborts the request and sends the exception to the master.
+pub trait Handler {
+    fn read_coil(&mut self, _address: u16) -> Result<CoilState, Exception> {
+        Err(Exception::IllegalFunction)
+    }
+
+    fn read_input(&mut self, _address: u16) -> Result<CoilState, Exception> {
+        Err(Exception::IllegalFunction)
+    }
+
+    fn read_output_register(&mut self, _address: u16) -> Result<u16, Exception> {
+        Err(Exception::IllegalFunction)
+    }
+
+    fn read_input_register(&mut self, _address: u16) -> Result<u16, Exception> {
+        Err(Exception::IllegalFunction)
+    }
+
+    fn set_coil(&mut self, _address: u16, _status: CoilState) -> Result<(), Exception> {
+        Err(Exception::IllegalFunction)
+    }
+
+    fn set_register(&mut self, _address: u16, _value: u16) -> Result<(), Exception> {
+        Err(Exception::IllegalFunction)
+    }
+
+    /// Reads a 32 bit holding register of an Enron Modbus device.
+    fn read_long_register(&mut self, _address: u16) -> Result<u32, Exception> {
+        Err(Exception::IllegalFunction)
+    }
+
+    fn set_long_register(&mut self, _address: u16, _value: u32) -> Result<(), Exception> {
+        Err(Exception::IllegalFunction)
+    }
+
+    /// Writes the unacknowledged events of the Enron Modbus event log into `records` and returns their length.
+    ///
+    /// The layout of an event record is defined by the device.
+    /// The same events are returned until they are acknowledged.
+    fn read_event_log(&mut self, _records: &mut [u8]) -> Result<usize, Exception> {
+        Err(Exception::IllegalFunction)
+    }
+
+    /// Removes the events returned by the last event log read from the Enron Modbus event log.
+    fn acknowledge_event_log(&mut self) -> Result<(), Exception> {
+        Err(Exception::IllegalFunction)
+    }
+
+    /// Returns the eight exception status outputs reported to a read exception status request.
+    fn read_exception_status(&mut self) -> Result<u8, Exception> {
+        Err(Exception::IllegalFunction)
+    }
+
+    /// Returns the files accessed by the read and write file record functions.
+    fn file_store(&mut self) -> Option<&mut dyn FileStore> {
+        None
+    }
+
+    /// Returns the FIFO queues read by the read FIFO queue function.
+    fn fifo_source(&mut self) -> Option<&mut dyn FifoSource> {
+        None
+    }
+
+    /// Executes a request to a user-defined function registered with `Modbus::register_function`.
+    ///
+    /// `pdu` is the request starting with the function code, borrowed directly from the receive buffer.
+    /// The response data following the function code is written to the start of `response`
+    /// and its length returned.
+    fn custom_function(&mut self, _pdu: &[u8], _response: &mut [u8]) -> Result<usize, Exception> {
+        Err(Exception::IllegalFunction)
+    }
+
+    /// Modifies single bits of a holding register.
+    ///
+    /// The default implementation reads the register, applies the masks and writes the result back.
+    /// This is atomic with respect to other requests as the handler is borrowed for the whole update.
+    /// Override it if the register is shared with something else which requires its own locking.
+    fn mask_write_register(
+        &mut self,
+        address: u16,
+        and_mask: u16,
+        or_mask: u16,
+    ) -> Result<(), Exception> {
+        let value = self.read_output_register(address)?;
+        self.set_register(address, (value & and_mask) | (or_mask & !and_mask))
+    }
+
+    /// Returns the objects the slave identifies itself with to a read device identification request.
+    fn device_identification(&self) -> Option<DeviceIdentification<'_>> {
+        None
+    }
+
+    /// Returns the server id and run indicator status reported to a report server id request.
+    fn server_id(&self) -> Option<ServerId<'_>> {
+        None
+    }
+}
+
+/// The files of a modbus slave.
+///
+/// A file consists of up to 10000 records, each holding a single register.
+/// Record data is passed in big endian byte order, directly from the received request
+/// or into the response being built respectively.
+/// Requests for file number 0, records beyond 9999 or a reference type other than 6
+/// are rejected before they reach the store.
+pub trait FileStore {
+    /// Reads `data.len() / 2` records starting at `record_number` into `data`.
+    fn read_records(
+        &mut self,
+        file_number: u16,
+        record_number: u16,
+        data: &mut [u8],
+    ) -> Result<(), Exception>;
+
+    /// Writes `data.len() / 2` records starting at `record_number`.
+    fn write_records(
+        &mut self,
+        file_number: u16,
+        record_number: u16,
+        data: &[u8],
+    ) -> Result<(), Exception>;
+}
+
+/// The FIFO queues of a modbus slave, each identified by its pointer address.
+///
+/// A read FIFO queue request returns all queued registers at once without removing them,
+/// the application decides when they are consumed.
+/// Queues holding more than 31 registers are answered with an Illegal Data Value exception.
+pub trait FifoSource {
+    /// Returns the number of registers queued in the FIFO at `pointer_address`.
+    fn fifo_count(&mut self, pointer_address: u16) -> Result<u16, Exception>;
+
+    /// Returns the queued register at `index`, the oldest register being at index 0.
+    fn read_fifo(&mut self, pointer_address: u16, index: u16) -> Result<u16, Exception>;
+}
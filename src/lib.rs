@@ -1,14 +1,49 @@
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 
 mod consts;
 mod data;
+mod device_identification;
+mod diagnostics;
+mod echo;
+mod enron;
 mod error;
+mod exception;
+mod firmware;
+mod gateway;
 mod general;
+mod handler;
+#[cfg(all(feature = "std", target_os = "linux"))]
+mod linux;
 mod modbus;
 mod request;
+mod response;
+mod rs485;
+mod serial;
+mod timing;
+#[cfg(any(feature = "tokio", feature = "embedded-io-async"))]
+mod transport;
 
 pub use data::CoilState;
+pub use device_identification::{object_id, DeviceIdentification, DeviceObject, ServerId};
+pub use diagnostics::Counters;
+pub use enron::{Enron, ENRON_LONG_REGISTERS};
 pub use error::Error;
+pub use exception::Exception;
+pub use firmware::{firmware_command, FirmwareUpdate, ImageSink, TransferState};
 pub use futures::{task::Poll, Future};
+pub use gateway::{Gateway, Route};
+pub use general::{BitwiseCrc, Crc, Crc16, DefaultCrc, NibbleCrc, SoftwareCrc, TableCrc};
+pub use handler::{FifoSource, FileStore, Handler};
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub use linux::{Parity, SerialConfig, SerialPort, StopBits};
 pub use modbus::Modbus;
-pub use request::{Request, RequestFrame};
+pub use request::{Request, RequestFrame, RequestLen};
+pub use rs485::{Rs485, TransmitError, Turnaround};
+pub use serial::SerialAdapter;
+pub use timing::{BusTiming, TimingStats};
+#[cfg(feature = "embedded-io-async")]
+pub use transport::EmbeddedIoTransport;
+#[cfg(feature = "tokio")]
+pub use transport::TokioTransport;
+#[cfg(any(feature = "tokio", feature = "embedded-io-async"))]
+pub use transport::TransportError;
@@ -0,0 +1,342 @@
+use crate::timing::BusTiming;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    mem::MaybeUninit,
+    os::unix::{
+        fs::OpenOptionsExt,
+        io::{AsRawFd, RawFd},
+    },
+    path::Path,
+};
+
+/// The flags of the kernel RS-485 configuration.
+const SER_RS485_ENABLED: u32 = 1 << 0;
+const SER_RS485_RTS_ON_SEND: u32 = 1 << 1;
+
+/// The kernel RS-485 configuration set with `TIOCSRS485`, see `struct serial_rs485` in `linux/serial.h`.
+#[repr(C)]
+struct SerialRs485 {
+    flags: u32,
+    delay_rts_before_send: u32,
+    delay_rts_after_send: u32,
+    padding: [u32; 5],
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+/// The character format and speed of a serial line.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SerialConfig {
+    pub baud_rate: u32,
+    pub parity: Parity,
+    /// The number of data bits, 5 to 8.
+    pub data_bits: u8,
+    pub stop_bits: StopBits,
+}
+
+impl SerialConfig {
+    /// Returns the default modbus RTU character format, 8 data bits with even parity and one stop bit.
+    pub fn new(baud_rate: u32) -> SerialConfig {
+        SerialConfig {
+            baud_rate,
+            parity: Parity::Even,
+            data_bits: 8,
+            stop_bits: StopBits::One,
+        }
+    }
+
+    /// Returns the number of bits of a single character, including the start, parity and stop bits.
+    pub fn character_bits(&self) -> u32 {
+        let parity = if self.parity == Parity::None { 0 } else { 1 };
+        let stop = match self.stop_bits {
+            StopBits::One => 1,
+            StopBits::Two => 2,
+        };
+        1 + self.data_bits as u32 + parity + stop
+    }
+
+    /// Returns the timing of the characters on the line, e.g. t3.5 to detect the end of a frame with.
+    pub fn bus_timing(&self) -> BusTiming {
+        BusTiming::new(self.baud_rate, self.character_bits())
+    }
+
+    /// Applies the configuration to raw terminal attributes.
+    fn apply(&self, termios: &mut libc::termios) -> io::Result<()> {
+        let speed = match self.baud_rate {
+            1200 => libc::B1200,
+            2400 => libc::B2400,
+            4800 => libc::B4800,
+            9600 => libc::B9600,
+            19200 => libc::B19200,
+            38400 => libc::B38400,
+            57600 => libc::B57600,
+            115_200 => libc::B115200,
+            230_400 => libc::B230400,
+            460_800 => libc::B460800,
+            921_600 => libc::B921600,
+            _ => return Err(invalid_input("unsupported baud rate")),
+        };
+        let size = match self.data_bits {
+            5 => libc::CS5,
+            6 => libc::CS6,
+            7 => libc::CS7,
+            8 => libc::CS8,
+            _ => return Err(invalid_input("unsupported number of data bits")),
+        };
+
+        unsafe { libc::cfmakeraw(termios) };
+        termios.c_cflag &= !(libc::CSIZE | libc::PARENB | libc::PARODD | libc::CSTOPB);
+        termios.c_cflag |= size | libc::CLOCAL | libc::CREAD;
+        match self.parity {
+            Parity::None => (),
+            Parity::Even => termios.c_cflag |= libc::PARENB,
+            Parity::Odd => termios.c_cflag |= libc::PARENB | libc::PARODD,
+        }
+        if self.stop_bits == StopBits::Two {
+            termios.c_cflag |= libc::CSTOPB;
+        }
+        // Block until at least a single byte was received.
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+
+        check(unsafe { libc::cfsetispeed(termios, speed) })?;
+        check(unsafe { libc::cfsetospeed(termios, speed) })?;
+        Ok(())
+    }
+}
+
+/// A serial port of Linux, e.g. `/dev/ttyUSB0` of a USB-RS485 adapter.
+pub struct SerialPort {
+    file: File,
+    config: SerialConfig,
+}
+
+impl SerialPort {
+    /// Opens the serial port at `path` and configures it in raw mode.
+    pub fn open<P: AsRef<Path>>(path: P, config: SerialConfig) -> io::Result<SerialPort> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(path)?;
+
+        let mut termios = MaybeUninit::<libc::termios>::uninit();
+        check(unsafe { libc::tcgetattr(file.as_raw_fd(), termios.as_mut_ptr()) })?;
+        let mut termios = unsafe { termios.assume_init() };
+        config.apply(&mut termios)?;
+        check(unsafe { libc::tcsetattr(file.as_raw_fd(), libc::TCSANOW, &termios) })?;
+
+        Ok(SerialPort { file, config })
+    }
+
+    pub fn config(&self) -> &SerialConfig {
+        &self.config
+    }
+
+    /// Lets the kernel drive the RTS line as driver enable of an RS-485 transceiver.
+    ///
+    /// RTS is asserted `delay_before_send` milliseconds before a transmission
+    /// and released `delay_after_send` milliseconds after it.
+    /// Fails if the driver of the serial port does not support the RS-485 mode.
+    pub fn enable_rs485(&self, delay_before_send: u32, delay_after_send: u32) -> io::Result<()> {
+        let rs485 = SerialRs485 {
+            flags: SER_RS485_ENABLED | SER_RS485_RTS_ON_SEND,
+            delay_rts_before_send: delay_before_send,
+            delay_rts_after_send: delay_after_send,
+            padding: [0; 5],
+        };
+        check(unsafe { libc::ioctl(self.file.as_raw_fd(), libc::TIOCSRS485, &rs485) })
+    }
+}
+
+impl Read for SerialPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for SerialPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    /// Waits until all written data was transmitted.
+    fn flush(&mut self) -> io::Result<()> {
+        check(unsafe { libc::tcdrain(self.file.as_raw_fd()) })
+    }
+}
+
+impl AsRawFd for SerialPort {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn invalid_input(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, Parity, SerialConfig, SerialPort, StopBits};
+    use std::{
+        ffi::CStr,
+        fs::File,
+        io::{Read, Write},
+        mem::MaybeUninit,
+        os::unix::io::{AsRawFd, FromRawFd},
+        ptr,
+    };
+
+    /// Opens a pseudo-terminal pair, returning the master and the path of the slave.
+    fn pty() -> (File, String, File) {
+        let (mut master, mut slave) = (0, 0);
+        check(unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                ptr::null_mut(),
+                ptr::null(),
+                ptr::null(),
+            )
+        })
+        .unwrap();
+        let path = unsafe { CStr::from_ptr(libc::ttyname(slave)) }
+            .to_str()
+            .unwrap()
+            .to_owned();
+        // The slave is kept open so the master does not see a hangup.
+        unsafe { (File::from_raw_fd(master), path, File::from_raw_fd(slave)) }
+    }
+
+    #[test]
+    fn timeouts() {
+        let config = SerialConfig::new(9600);
+        assert_eq!(config.character_bits(), 11);
+        assert_eq!(config.bus_timing().t1_5, 1718);
+        assert_eq!(config.bus_timing().t3_5, 4010);
+
+        let config = SerialConfig {
+            parity: Parity::None,
+            ..SerialConfig::new(19200)
+        };
+        assert_eq!(config.character_bits(), 10);
+        assert_eq!(config.bus_timing().t3_5, 1822);
+
+        let config = SerialConfig::new(115_200);
+        assert_eq!(config.bus_timing().t1_5, 750);
+        assert_eq!(config.bus_timing().t3_5, 1750);
+    }
+
+    #[test]
+    fn open_pty() {
+        let (mut master, path, _slave) = pty();
+        let config = SerialConfig {
+            parity: Parity::Odd,
+            stop_bits: StopBits::Two,
+            ..SerialConfig::new(19200)
+        };
+        let mut port = SerialPort::open(&path, config).unwrap();
+
+        let mut termios = MaybeUninit::<libc::termios>::uninit();
+        check(unsafe { libc::tcgetattr(port.as_raw_fd(), termios.as_mut_ptr()) }).unwrap();
+        let termios = unsafe { termios.assume_init() };
+        assert_eq!(unsafe { libc::cfgetospeed(&termios) }, libc::B19200);
+        assert_eq!(termios.c_cflag & libc::CSIZE, libc::CS8);
+        assert_ne!(termios.c_cflag & libc::PARODD, 0);
+        assert_ne!(termios.c_cflag & libc::CSTOPB, 0);
+
+        master.write_all(&[0x11, 0x03]).unwrap();
+        let mut buf = [0; 2];
+        port.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0x11, 0x03]);
+
+        port.write_all(&[0x11, 0x83, 0x02]).unwrap();
+        let mut buf = [0; 3];
+        master.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0x11, 0x83, 0x02]);
+
+        // A pseudo-terminal has no RS-485 mode.
+        assert!(port.enable_rs485(0, 0).is_err());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn tokio_transport() {
+        use crate::{Exception, Handler, Modbus, TokioTransport};
+        use bbqueue::{atomic::consts::U2048, BBBuffer};
+        use futures::future::{select, Either};
+        use std::time::Duration;
+
+        struct Register(u16);
+
+        impl Handler for Register {
+            fn read_output_register(&mut self, address: u16) -> Result<u16, Exception> {
+                match address {
+                    0 => Ok(self.0),
+                    _ => Err(Exception::IllegalDataAddress),
+                }
+            }
+        }
+
+        let (mut master, path, _slave) = pty();
+        let port = SerialPort::open(&path, SerialConfig::new(19200)).unwrap();
+        // tokio reads files in its blocking thread pool, which is cancel safe.
+        let reader = tokio::fs::File::from_std(port.file.try_clone().unwrap());
+        let writer = tokio::fs::File::from_std(port.file);
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = Modbus::new(&bb);
+
+        // A garbage byte followed by an idle line and a valid request.
+        let master = tokio::task::spawn_blocking(move || {
+            master.write_all(&[0x11]).unwrap();
+            std::thread::sleep(Duration::from_millis(100));
+            master
+                .write_all(&[0x11, 0x03, 0x00, 0x00, 0x00, 0x01, 0x86, 0x9A])
+                .unwrap();
+            let mut response = [0; 7];
+            master.read_exact(&mut response).unwrap();
+            (master, response)
+        });
+        {
+            let mut transport = TokioTransport::new(reader, writer, Duration::from_millis(10));
+            let mut register = Register(42);
+            let run = transport.run(&mut modbus, &mut register);
+            futures::pin_mut!(run);
+            let (master, response) = match select(run, master).await {
+                Either::Left((result, _)) => panic!("transport ended with {:?}", result),
+                Either::Right((master, _)) => master.unwrap(),
+            };
+            assert_eq!(response, [0x11, 0x03, 0x02, 0x00, 0x2A, 0xF8, 0x58]);
+            // Closing the master ends the read still pending in the thread pool.
+            drop(master);
+        }
+        assert_eq!(modbus.counters().bus_message, 2);
+    }
+
+    #[test]
+    fn unsupported_baud_rate() {
+        let (_master, path, _slave) = pty();
+        assert!(SerialPort::open(&path, SerialConfig::new(12345)).is_err());
+    }
+}
@@ -3,9 +3,18 @@ use bbqueue::atomic::BBBuffer;
 #[cfg(not(feature = "atomic"))]
 use bbqueue::cm_mutex::BBBuffer;
 
+use crate::consts;
+use crate::diagnostics::{self, event, Counters, Diagnostics};
+use crate::echo::Echo;
+use crate::enron::Enron;
 use crate::error::Error;
-use crate::request::RequestFrame;
-use bbqueue::{ArrayLength, Consumer, Producer};
+use crate::exception::Exception;
+use crate::general::{Crc16, SoftwareCrc};
+use crate::handler::Handler;
+use crate::request::{CustomFunctions, Request, RequestFrame, RequestLen};
+use crate::response::ResponseWriter;
+use crate::timing::{BusTiming, Timing, TimingStats};
+use bbqueue::{ArrayLength, Consumer, GrantR, GrantW, Producer};
 use core::{
     pin::Pin,
     task::{Context, Waker},
@@ -17,6 +26,34 @@ pub struct Modbus<'a, S: ArrayLength<u8>> {
     consumer: Consumer<'a, S>,
     waker: Option<Waker>,
     needed_bytes: Option<usize>,
+    slave_id: Option<u8>,
+    diagnostics: Diagnostics,
+    custom_functions: CustomFunctions,
+    enron: Option<Enron<'a>>,
+    /// The echo to suppress, if echo suppression is enabled.
+    echo: Option<Echo>,
+    /// Whether a bus collision was detected which was not reported yet.
+    collision: bool,
+    /// Whether the line went idle after the last received byte.
+    idle: bool,
+    /// The queue responses are encoded in by `serve_queued`, if set.
+    transmit: Option<(Producer<'a, S>, Consumer<'a, S>)>,
+    /// The task waiting for the transmit queue to drain.
+    transmit_waker: Option<Waker>,
+    /// The analysis of the timestamps of the received data, if enabled.
+    timing: Option<Timing>,
+    /// Whether frames with a silent interval longer than t1.5 are rejected.
+    strict_timing: bool,
+    /// The number of bytes received and consumed as frames, wrapping around.
+    received: usize,
+    consumed: usize,
+    /// The position in the received bytes of a silent interval longer than t1.5, if one was measured.
+    illegal_gap: Option<usize>,
+    /// The CRC of the frame being received, computed in software unless `custom_crc` is set.
+    crc: SoftwareCrc,
+    custom_crc: Option<&'a mut (dyn Crc16 + Send)>,
+    /// The number of bytes of the frame being received the CRC was computed over.
+    crc_len: usize,
 }
 
 impl<'a, S: ArrayLength<u8> + 'a> Modbus<'a, S> {
@@ -28,31 +65,474 @@ impl<'a, S: ArrayLength<u8> + 'a> Modbus<'a, S> {
             consumer,
             waker: None,
             needed_bytes: None,
+            slave_id: None,
+            diagnostics: Diagnostics::new(),
+            custom_functions: CustomFunctions::new(),
+            enron: None,
+            echo: None,
+            collision: false,
+            idle: false,
+            transmit: None,
+            transmit_waker: None,
+            timing: None,
+            strict_timing: false,
+            received: 0,
+            consumed: 0,
+            illegal_gap: None,
+            crc: SoftwareCrc::new(),
+            custom_crc: None,
+            crc_len: 0,
         }
     }
 
     /// Call this in the data received interrupt.
     pub fn on_data_received(&mut self, data: &[u8]) {
+        let data = match self.strip_echo(data) {
+            Some(data) => data,
+            None => return,
+        };
+
         // Get a grant that is as large as the size of the received data.
-        // Additionally the grant needs to be large enough to fit a possible second frame without any fragmenation.
-        // Thus, we reserve the remaining bytes we expect for this frame (defaulting to 0)
-        // plus 256 bytes (the max size of any frame) for the next frame.
-        // This guarantees no fragmentation for the frames received. If our DMA transfer unit
-        // is chosen smaller than the max frame size (256 bytes), it is guaranteed we never contain more than two frames in the data.
-        // This is a bit wasteful with memory but guarantees no fragmentation.
-        let mut wgr = self
-            .producer
-            .grant_exact(data.len())
-            .unwrap_or_else(|_| panic!());
+        let mut wgr = match self.grant(data.len()) {
+            Some(wgr) => wgr,
+            None => {
+                // There is no room left for the received data, so it is lost.
+                self.count_overrun();
+                return;
+            }
+        };
 
         // Copy the data from the receive buffer into the bbqueue.
-        wgr.clone_from_slice(&data);
+        wgr.clone_from_slice(data);
 
         // Make sure we commit the stored bytes.
         wgr.commit(data.len());
+        self.on_committed(data.len());
+    }
+
+    /// Like `on_data_received`, but with the time the last byte of `data` was received at in microseconds.
+    ///
+    /// The timestamps are used to measure the silent intervals on the bus, see `set_bus_timing`.
+    /// They may come from a free running timer which wraps around.
+    pub fn on_data_received_at(&mut self, data: &[u8], timestamp: u32) {
+        if let Some(timing) = self.timing.as_mut() {
+            if timing.received(data.len(), timestamp) && self.strict_timing {
+                // Only the first gap is kept until the frame it is in was received.
+                self.illegal_gap.get_or_insert(self.received);
+            }
+        }
+        self.on_data_received(data);
+    }
+
+    /// Returns a grant of `len` bytes of the receive buffer for a DMA engine to write to.
+    ///
+    /// The received bytes are handed over with `commit_received` without copying them.
+    /// Only a single grant can be held at a time, `on_data_received` cannot store any data meanwhile.
+    /// Returns `None` if there is no room for `len` bytes, which is counted as a character overrun.
+    pub fn receive_grant(&mut self, len: usize) -> Option<GrantW<'a, S>> {
+        let wgr = self.grant(len);
+        if wgr.is_none() {
+            self.count_overrun();
+        }
+        wgr
+    }
+
+    /// Commits the first `len` bytes the DMA engine wrote to `wgr`.
+    ///
+    /// Call this when the DMA transfer completed, or in the idle line interrupt
+    /// with the number of bytes transferred so far, followed by `on_idle_line`.
+    pub fn commit_received(&mut self, mut wgr: GrantW<'a, S>, len: usize) {
+        let len = core::cmp::min(len, wgr.len());
+        let echo_len = match self.strip_echo(&wgr[..len]) {
+            Some(data) => len - data.len(),
+            // Dropping the grant commits nothing.
+            None => return,
+        };
+
+        // Move the received data in front of the echo.
+        wgr.copy_within(echo_len..len, 0);
+        wgr.commit(len - echo_len);
+        self.on_committed(len - echo_len);
+    }
+
+    /// Call this in the idle line interrupt, or once a timer expired 3.5 characters after the last received byte.
+    ///
+    /// It marks a frame boundary, all bytes received so far belong to complete frames.
+    /// A frame which is still incomplete was cut short, it is dropped and the next request yields `Error::InvalidFrame`.
+    /// A frame of an unknown function is dropped as well, yielding `Error::UnknownFunction`.
+    /// Without calling this, the receiver is stuck on such a frame.
+    pub fn on_idle_line(&mut self) {
+        self.idle = true;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Call this when the UART reported a receive error, like a framing, parity or overrun error.
+    ///
+    /// The error is counted as a character overrun. The frame being received is cut short
+    /// like on an idle line, so it is dropped instead of being checked against its CRC.
+    pub fn on_receive_error(&mut self) {
+        self.count_overrun();
+        self.on_idle_line();
+    }
+
+    pub async fn next(&mut self) -> Result<RequestFrame<'_, S>, Error> {
+        self.receive().await
+    }
+
+    /// Receives the next request, executes it on the handler and writes the response into `buf`.
+    ///
+    /// Returns the length of the response or `None` if the request must not be answered.
+    /// `buf` has to hold at least 256 bytes, the maximum length of a modbus RTU frame.
+    /// Diagnostics requests are answered from the state of the receiver, all other requests by the handler.
+    pub async fn serve<H: Handler>(
+        &mut self,
+        handler: &mut H,
+        buf: &mut [u8],
+    ) -> Result<Option<usize>, Error> {
+        let frame = match self.receive().await {
+            Ok(frame) => frame,
+            Err(e) => {
+                if e == Error::Crc {
+                    self.diagnostics
+                        .log_receive(event::RECEIVE_COMMUNICATION_ERROR);
+                }
+                return Err(e);
+            }
+        };
+
+        // Ignore requests addressed to other slaves.
+        if let Some(slave_id) = self.slave_id {
+            if frame.slave_id != slave_id as usize && frame.slave_id != 0 {
+                return Ok(None);
+            }
+        }
+        diagnostics::count(&mut self.diagnostics.counters.server_message);
+
+        let broadcast = frame.slave_id == 0;
+        self.diagnostics.log_receive(if broadcast {
+            event::RECEIVE_BROADCAST
+        } else {
+            0
+        });
+
+        let len = match frame.request {
+            // A slave in listen only mode monitors the bus but neither acts on requests nor answers them,
+            // not even with an exception. Only a restart communications option ends the listen only mode.
+            Request::Diagnostics { sub_function, data } if self.diagnostics.listen_only => {
+                self.diagnostics.listen(sub_function, data);
+                None
+            }
+            _ if self.diagnostics.listen_only => None,
+            Request::Diagnostics { sub_function, data } => {
+                let mut response =
+                    ResponseWriter::new(buf, frame.slave_id as u8, frame.request.function_code());
+                match self.diagnostics.execute(sub_function, data, &mut response) {
+                    Ok(true) => Some(response.finish()),
+                    Ok(false) => None,
+                    Err(exception) => Some(response.exception(exception)),
+                }
+            }
+            Request::GetCommEventCounter => {
+                let mut response =
+                    ResponseWriter::new(buf, frame.slave_id as u8, frame.request.function_code());
+                self.diagnostics.comm_event_counter(&mut response);
+                Some(response.finish())
+            }
+            Request::GetCommEventLog => {
+                let mut response =
+                    ResponseWriter::new(buf, frame.slave_id as u8, frame.request.function_code());
+                self.diagnostics.comm_event_log(&mut response);
+                Some(response.finish())
+            }
+            _ => Some(frame.write_response(handler, buf)),
+        };
+
+        let len = match len {
+            Some(len) => len,
+            None => {
+                diagnostics::count(&mut self.diagnostics.counters.server_no_response);
+                return Ok(None);
+            }
+        };
+
+        // Exception responses have the MSB of the function code set.
+        let exception = if buf[1] & 0x80 != 0 {
+            Some(buf[2])
+        } else {
+            None
+        };
+
+        // The event counter counts successfully completed requests, except for the ones fetching it.
+        match frame.request {
+            Request::GetCommEventCounter | Request::GetCommEventLog => (),
+            _ if exception.is_none() => self.diagnostics.count_event(),
+            _ => (),
+        }
+
+        if broadcast {
+            diagnostics::count(&mut self.diagnostics.counters.server_no_response);
+            return Ok(None);
+        }
+
+        if let Some(code) = exception {
+            diagnostics::count(&mut self.diagnostics.counters.server_exception);
+            if code == Exception::NegativeAcknowledge as u8 {
+                diagnostics::count(&mut self.diagnostics.counters.server_nak);
+            } else if code == Exception::ServerDeviceBusy as u8 {
+                diagnostics::count(&mut self.diagnostics.counters.server_busy);
+            }
+        }
+        self.diagnostics.log_send(exception);
+
+        Ok(Some(len))
+    }
+
+    /// Receives the next request like `serve`, but encodes the response in place in the transmit queue.
+    ///
+    /// Waits for the previous response to be sent first, as a slave answers one request at a time.
+    /// Fails with `Error::NoTransmitBuffer` without receiving anything if no transmit buffer was set.
+    pub async fn serve_queued<H: Handler>(
+        &mut self,
+        handler: &mut H,
+    ) -> Result<Option<usize>, Error> {
+        self.transmitted().await;
+
+        let (producer, _) = self.transmit.as_mut().ok_or(Error::NoTransmitBuffer)?;
+        // A whole frame always fits into the drained queue.
+        let mut wgr = producer
+            .grant_exact(consts::MAX_FRAME_LEN)
+            .unwrap_or_else(|_| panic!());
+        // Dropping the grant without a response commits nothing.
+        let len = self.serve(handler, &mut wgr).await?;
+        if let Some(len) = len {
+            wgr.commit(len);
+        }
+        Ok(len)
+    }
+
+    /// Sets the buffer responses are queued in by `serve_queued`.
+    ///
+    /// The buffer has to hold at least 512 bytes, such that a whole frame fits in once it was drained.
+    pub fn set_transmit_buffer(&mut self, bb: &'a BBBuffer<S>) {
+        self.transmit = Some(bb.try_split().unwrap_or_else(|_| panic!()));
+    }
+
+    /// Returns the queued bytes for the UART or DMA engine to send, if there are any.
+    ///
+    /// Call this in the transmit interrupt and hand the grant back with `on_data_sent`.
+    pub fn transmit_grant(&mut self) -> Option<GrantR<'a, S>> {
+        self.transmit.as_mut()?.1.read().ok()
+    }
+
+    /// Releases the first `len` bytes of `rgr` from the transmit queue once they were sent.
+    ///
+    /// For an RS-485 transceiver, call this once the transmission completed,
+    /// not as soon as the bytes were moved to the UART.
+    pub fn on_data_sent(&mut self, rgr: GrantR<'a, S>, len: usize) {
+        let len = core::cmp::min(len, rgr.len());
+        self.on_data_transmitted(&rgr[..len]);
+        rgr.release(len);
+
+        if self.transmit_queue_empty() {
+            if let Some(waker) = self.transmit_waker.take() {
+                waker.wake();
+            }
+        }
+    }
 
-        // TODO: Handle wraparound.
+    /// Waits until all queued responses were sent, e.g. to release the driver enable of an RS-485 transceiver.
+    pub async fn transmitted(&mut self) {
+        struct TransmittedFuture<'a: 'b, 'b, S: ArrayLength<u8>> {
+            bus: &'b mut Modbus<'a, S>,
+        }
+
+        impl<'a: 'b, 'b, S: ArrayLength<u8> + 'a> Future for TransmittedFuture<'a, 'b, S> {
+            type Output = ();
+
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+                if self.bus.transmit_queue_empty() {
+                    Poll::Ready(())
+                } else {
+                    self.bus.transmit_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }
+
+        TransmittedFuture { bus: self }.await
+    }
+
+    /// Computes the CRC of received frames with `crc`, e.g. a hardware CRC unit, instead of in software.
+    ///
+    /// `crc` has to be `Send`, such that the receiver stays `Send` as well.
+    pub fn set_crc(&mut self, crc: &'a mut (dyn Crc16 + Send)) {
+        crc.reset();
+        self.custom_crc = Some(crc);
+        self.crc_len = 0;
+    }
+
+    /// Enables the measurement of the silent intervals from the timestamps passed to `on_data_received_at`.
+    pub fn set_bus_timing(&mut self, timing: BusTiming) {
+        self.timing = Some(Timing::new(timing));
+    }
+
+    /// Enables or disables the rejection of frames with a silent interval longer than t1.5.
+    ///
+    /// The modbus specification declares such a frame as incomplete, so it yields `Error::InvalidFrame`.
+    /// Takes effect with the bus timing set only.
+    pub fn set_strict_timing(&mut self, strict: bool) {
+        self.strict_timing = strict;
+    }
+
+    /// Returns the statistics on the silent intervals on the bus, all zero without the bus timing set.
+    pub fn timing_stats(&self) -> TimingStats {
+        self.timing
+            .as_ref()
+            .map(|timing| timing.stats)
+            .unwrap_or_default()
+    }
+
+    /// Sets the id this slave answers to.
+    ///
+    /// Requests addressed to other slaves are ignored by `serve`.
+    /// As long as no id is set, all requests are answered.
+    pub fn set_slave_id(&mut self, slave_id: u8) {
+        self.slave_id = Some(slave_id);
+    }
+
+    /// Puts the slave into or takes it out of listen only mode.
+    ///
+    /// In listen only mode `serve` keeps receiving requests and updating the counters,
+    /// but never acts on or answers a request.
+    /// Besides calling this, a master ends the listen only mode with a restart communications option.
+    pub fn set_listen_only(&mut self, listen_only: bool) {
+        if listen_only && !self.diagnostics.listen_only {
+            self.diagnostics.enter_listen_only();
+        } else {
+            self.diagnostics.listen_only = listen_only;
+        }
+    }
+
+    /// Marks the slave as busy processing a previous command.
+    ///
+    /// The busy state is reported in the status word of the comm event counter and log.
+    pub fn set_busy(&mut self, busy: bool) {
+        self.diagnostics.busy = busy;
+    }
+
+    /// Returns whether the slave is in listen only mode.
+    pub fn is_listen_only(&self) -> bool {
+        self.diagnostics.listen_only
+    }
+
+    /// Returns the diagnostic counters of the serial line.
+    ///
+    /// The bus counters are maintained by the receiver, the server counters by `serve`.
+    pub fn counters(&self) -> Counters {
+        self.diagnostics.counters
+    }
+
+    /// Resets all diagnostic counters to zero.
+    pub fn clear_counters(&mut self) {
+        self.diagnostics.counters = Counters::default();
+    }
+
+    /// Sets the value of the diagnostic register reported to the master.
+    pub fn set_diagnostic_register(&mut self, value: u16) {
+        self.diagnostics.register = value;
+    }
+
+    /// Registers a user-defined function.
+    ///
+    /// Only the function codes 65-72 and 100-110 are reserved for user-defined functions,
+    /// registering any other function code fails.
+    /// `request_len` determines the length of a request to the function while it is received.
+    /// Received requests are passed as `Request::Custom` to `Handler::custom_function`.
+    pub fn register_function(
+        &mut self,
+        function_code: u8,
+        request_len: RequestLen,
+    ) -> Result<(), Error> {
+        self.custom_functions.register(function_code, request_len)
+    }
+
+    /// Enables the Enron Modbus extension with 32 bit registers and the event log.
+    pub fn set_enron(&mut self, enron: Enron<'a>) {
+        self.enron = Some(enron);
+    }
+
+    /// Enables or disables the suppression of the echo of our own transmissions.
+    ///
+    /// Some transceivers loop the transmitted data back into the receive path.
+    /// With echo suppression enabled, everything passed to `on_data_transmitted` is expected back
+    /// and discarded when received. If the received data differs, the next request yields
+    /// `Error::BusCollision` instead.
+    pub fn set_echo_suppression(&mut self, enabled: bool) {
+        self.echo = if enabled { Some(Echo::new()) } else { None };
+    }
+
+    /// Call this with the data handed to the transmitter.
+    pub fn on_data_transmitted(&mut self, data: &[u8]) {
+        if let Some(echo) = self.echo.as_mut() {
+            echo.transmitted(data);
+        }
+    }
+
+    /// Strips the echo of our own transmissions off the received data.
+    ///
+    /// Returns `None` if nothing but echo was received or a bus collision was detected.
+    fn strip_echo<'d>(&mut self, data: &'d [u8]) -> Option<&'d [u8]> {
+        match self.echo.as_mut().map(|echo| echo.strip(data)) {
+            Some(Ok([])) => None,
+            Some(Ok(data)) => Some(data),
+            Some(Err(_)) => {
+                // The collision is reported by the next poll for a request.
+                self.collision = true;
+                if let Some(waker) = self.waker.take() {
+                    waker.wake();
+                }
+                None
+            }
+            None => Some(data),
+        }
+    }
+
+    /// Returns a grant of `len` bytes of the receive buffer which directly follows the received bytes.
+    ///
+    /// A frame has to be contiguous in the buffer to be parsed. So if the grant wraps around the end of the buffer,
+    /// the received bytes which were not taken yet are moved to its start first, in front of the new grant.
+    fn grant(&mut self, len: usize) -> Option<GrantW<'a, S>> {
+        let wgr = self.producer.grant_exact(len).ok()?;
+        let rgr = match self.consumer.read() {
+            Ok(rgr) => rgr,
+            // Nothing was received which would have to be continued.
+            Err(_) => return Some(wgr),
+        };
+        if wgr.as_ptr() >= rgr.as_ptr() {
+            return Some(wgr);
+        }
+
+        // Dropping the grant commits nothing, the buffer stays wrapped around.
+        drop(wgr);
+        let pending = rgr.len();
+        let mut moved = self.producer.grant_exact(pending).ok()?;
+        moved.copy_from_slice(&rgr);
+        moved.commit(pending);
+        rgr.release(pending);
+        self.producer.grant_exact(len).ok()
+    }
+
+    /// Wakes the poller once `len` newly received bytes were committed.
+    fn on_committed(&mut self, len: usize) {
+        // The received bytes continue the last frame, if any.
+        self.idle = false;
+        self.received = self.received.wrapping_add(len);
+
+        // Frames never wrap around the end of the buffer, see `grant`.
         let rgr = self.consumer.read().unwrap_or_else(|_| panic!());
+        self.update_crc(&rgr);
         if let Some(needed_bytes) = self.needed_bytes {
             // If we don't need anymore bytes, call the waker.
             if rgr.len() >= needed_bytes {
@@ -69,7 +549,116 @@ impl<'a, S: ArrayLength<u8> + 'a> Modbus<'a, S> {
         }
     }
 
-    pub async fn next(&mut self) -> Result<RequestFrame<'_, S>, Error> {
+    fn crc(&mut self) -> &mut dyn Crc16 {
+        match self.custom_crc.as_mut() {
+            Some(crc) => &mut **crc,
+            None => &mut self.crc,
+        }
+    }
+
+    /// Feeds the newly received bytes of the frame being received into its CRC.
+    fn update_crc(&mut self, received: &[u8]) {
+        let frame_len = match self.needed_bytes {
+            Some(frame_len) => frame_len,
+            None => match RequestFrame::<S>::parse_request_len(
+                received,
+                &self.custom_functions,
+                self.enron.as_ref(),
+                false,
+            ) {
+                Ok(Some(frame_len)) => frame_len,
+                // The header is incomplete, so all received bytes belong to the frame.
+                Ok(None) => received.len(),
+                // The frame cannot be received anyway.
+                Err(_) => return,
+            },
+        };
+
+        let end = core::cmp::min(frame_len, received.len());
+        if end > self.crc_len {
+            let start = self.crc_len;
+            self.crc().update(&received[start..end]);
+            self.crc_len = end;
+        }
+    }
+
+    /// Completes the CRC of a received frame and returns whether it is valid.
+    ///
+    /// The bytes of the frame which were not received separately, e.g. a frame following another one
+    /// in the same chunk, are fed into the CRC only now.
+    fn finish_crc(&mut self, frame: &[u8]) -> bool {
+        if self.crc_len < frame.len() {
+            let start = self.crc_len;
+            self.crc().update(&frame[start..]);
+        }
+        // The CRC over a frame including its CRC is zero.
+        let valid = self.crc().get() == 0;
+        self.reset_crc();
+        valid
+    }
+
+    fn reset_crc(&mut self) {
+        self.crc().reset();
+        self.crc_len = 0;
+    }
+
+    fn count_overrun(&mut self) {
+        diagnostics::count(&mut self.diagnostics.counters.bus_character_overrun);
+        self.diagnostics
+            .log_receive(event::RECEIVE_CHARACTER_OVERRUN);
+    }
+
+    /// Returns whether an illegal gap was measured within the frame of `frame_len` bytes received next.
+    fn take_illegal_gap(&mut self, frame_len: usize) -> bool {
+        let offset = match self.illegal_gap {
+            Some(position) => position.wrapping_sub(self.consumed),
+            None => return false,
+        };
+        // A gap in a later frame is kept.
+        if offset >= frame_len {
+            return false;
+        }
+        self.illegal_gap = None;
+        // A gap in front of this frame is not within it.
+        offset > 0
+    }
+
+    /// Drops the first `len` received bytes, which hold a frame that cannot be received.
+    ///
+    /// The frame counts as a bus message, but only CRC errors count as communication errors.
+    fn drop_frame(
+        &mut self,
+        rgr: GrantR<'a, S>,
+        len: usize,
+        error: Error,
+    ) -> Result<RequestFrame<'a, S>, Error> {
+        rgr.release(len);
+        self.take_illegal_gap(len);
+        self.consumed = self.consumed.wrapping_add(len);
+        self.reset_crc();
+        self.count_frame(Err(error))
+    }
+
+    fn transmit_queue_empty(&mut self) -> bool {
+        match self.transmit.as_mut() {
+            Some((_, consumer)) => matches!(consumer.read(), Err(bbqueue::Error::InsufficientSize)),
+            None => true,
+        }
+    }
+
+    /// Updates the bus counters with the outcome of a received frame.
+    fn count_frame(
+        &mut self,
+        frame: Result<RequestFrame<'a, S>, Error>,
+    ) -> Result<RequestFrame<'a, S>, Error> {
+        diagnostics::count(&mut self.diagnostics.counters.bus_message);
+        if let Err(Error::Crc) = frame {
+            diagnostics::count(&mut self.diagnostics.counters.bus_communication_error);
+        }
+        frame
+    }
+
+    async fn receive(&mut self) -> Result<RequestFrame<'a, S>, Error> {
         struct RequestFuture<'a: 'b, 'b, S: ArrayLength<u8>> {
             bus: &'b mut Modbus<'a, S>,
         }
@@ -78,54 +667,74 @@ impl<'a, S: ArrayLength<u8> + 'a> Modbus<'a, S> {
             type Output = Result<RequestFrame<'a, S>, Error>;
 
             fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-                match self.bus.needed_bytes {
-                    Some(frame_len) => {
-                        // Read the stored bytes.
-                        let rgr = self.bus.consumer.read().unwrap_or_else(|_| panic!());
-                        let mut rgr = rgr.into_auto_release();
-                        rgr.to_release(frame_len);
+                if self.bus.collision {
+                    self.bus.collision = false;
+                    return Poll::Ready(Err(Error::BusCollision));
+                }
 
-                        if rgr.len() >= frame_len {
-                            // We don't require anymore bytes to parse the next frame.
-                            // So we reset everything and parse the frame.
-
-                            // Reset needed bytes to unknown for the next frame.
-                            self.bus.needed_bytes = None;
-                            // Parse and return the frame from the stored bytes.
-                            Poll::Ready(RequestFrame::parse_frame(rgr, frame_len))
-                        } else {
-                            // Wait on for more bytes.
-                            Poll::Pending
+                self.bus.waker = Some(cx.waker().clone());
+                // Read the stored bytes.
+                let rgr = match self.bus.consumer.read() {
+                    Ok(rgr) => rgr,
+                    // Nothing was received yet.
+                    Err(_) => return Poll::Pending,
+                };
+
+                if self.bus.needed_bytes.is_none() {
+                    match RequestFrame::<S>::parse_request_len(
+                        &rgr[..],
+                        &self.bus.custom_functions,
+                        self.bus.enron.as_ref(),
+                        self.bus.idle,
+                    ) {
+                        // We store the number of needed bytes, whether it is known or unknown (None, Some(len)).
+                        Ok(len) => self.bus.needed_bytes = len,
+                        // If an unknown function is encountered we cannot parse the frame length
+                        // and thus we cannot parse the entire frame.
+                        // Once the line went idle, the frame ends with the received bytes and can be dropped.
+                        Err(e) if self.bus.idle => {
+                            let len = rgr.len();
+                            return Poll::Ready(self.bus.drop_frame(rgr, len, e));
                         }
+                        // Until then, wait on for the idle line, see `on_idle_line`.
+                        Err(_) => return Poll::Pending,
                     }
-                    None => {
-                        self.bus.waker = Some(cx.waker().clone());
-                        // Read the stored bytes.
-                        let rgr = self.bus.consumer.read().unwrap_or_else(|_| panic!());
-                        match RequestFrame::<S>::parse_request_len(&rgr[..]) {
-                            Ok(len) => {
-                                // We store the number of needed bytes, whether it is known or unknown (None, Some(len)).
-                                self.bus.needed_bytes = len;
-                                // Instantly check if we can yield a new frame!
-                                if let Some(frame_len) = self.bus.needed_bytes {
-                                    // If we don't need anymore bytes, call the waker.
-                                    if rgr.len() >= frame_len {
-                                        self.bus.needed_bytes = None;
-                                        // Parse and return the frame from the stored bytes.
-                                        let mut rgr = rgr.into_auto_release();
-                                        rgr.to_release(frame_len);
-                                        return Poll::Ready(RequestFrame::parse_frame(rgr, frame_len));
-                                    }
-                                }
-                            }
-                            // If an unknown function is encountered we cannot parse the frame length
-                            // and thus we cannot parse the entire frame.
-                            // For now we just panic here.
-                            // TODO: Implement a recovery mechanism. Maybe a timeout?
-                            Err(_e) => unimplemented!("An unknown function id was encountered; How do we handle this properly?")
+                }
+
+                match self.bus.needed_bytes {
+                    // We don't require anymore bytes to parse the next frame.
+                    Some(frame_len) if rgr.len() >= frame_len => {
+                        // Reset needed bytes to unknown for the next frame.
+                        self.bus.needed_bytes = None;
+                        if self.bus.take_illegal_gap(frame_len) {
+                            // The frame was interrupted by a silent interval longer than t1.5.
+                            return Poll::Ready(self.bus.drop_frame(
+                                rgr,
+                                frame_len,
+                                Error::InvalidFrame,
+                            ));
                         }
-                        Poll::Pending
+                        self.bus.consumed = self.bus.consumed.wrapping_add(frame_len);
+                        let crc_valid = self.bus.finish_crc(&rgr[..frame_len]);
+                        // Parse and return the frame from the stored bytes.
+                        let mut rgr = rgr.into_auto_release();
+                        rgr.to_release(frame_len);
+                        let frame = RequestFrame::parse_frame(
+                            rgr,
+                            frame_len,
+                            crc_valid,
+                            self.bus.enron.as_ref(),
+                        );
+                        Poll::Ready(self.bus.count_frame(frame))
                     }
+                    // The line went idle before the frame was complete, so it was cut short.
+                    _ if self.bus.idle => {
+                        self.bus.needed_bytes = None;
+                        let len = rgr.len();
+                        Poll::Ready(self.bus.drop_frame(rgr, len, Error::InvalidFrame))
+                    }
+                    // Wait on for more bytes.
+                    _ => Poll::Pending,
                 }
             }
         }
@@ -136,8 +745,37 @@ impl<'a, S: ArrayLength<u8> + 'a> Modbus<'a, S> {
 
 #[cfg(test)]
 mod tests {
-    use crate::{CoilState, Error, Modbus, Request, RequestFrame};
-    use bbqueue::{atomic::consts::U2048, BBBuffer};
+    use crate::{
+        object_id, BusTiming, CoilState, Counters, Crc16, DeviceIdentification, DeviceObject,
+        Enron, Error, Exception, FifoSource, FileStore, Handler, Modbus, Request, RequestFrame,
+        ServerId, SoftwareCrc, TimingStats, ENRON_LONG_REGISTERS,
+    };
+    use bbqueue::{
+        atomic::consts::{U16, U2048, U32},
+        BBBuffer,
+    };
+    use futures::FutureExt;
+
+    /// A handler with eight holding registers.
+    struct Registers([u16; 8]);
+
+    impl Handler for Registers {
+        fn read_output_register(&mut self, address: u16) -> Result<u16, Exception> {
+            self.0
+                .get(address as usize)
+                .copied()
+                .ok_or(Exception::IllegalDataAddress)
+        }
+
+        fn set_register(&mut self, address: u16, value: u16) -> Result<(), Exception> {
+            let register = self
+                .0
+                .get_mut(address as usize)
+                .ok_or(Exception::IllegalDataAddress)?;
+            *register = value;
+            Ok(())
+        }
+    }
 
     #[tokio::test]
     async fn fn1_crc_correct() {
@@ -395,6 +1033,20 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn fn15_byte_count_mismatch() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut buf = [0; 256];
+
+        // 1968 coils in a single byte.
+        let data = [0x11, 0x0F, 0x00, 0x00, 0x07, 0xB0, 0x01, 0xFF, 0x3E, 0x88];
+        modbus.on_data_received(&data);
+        let frame = modbus.next().await.unwrap();
+        let len = frame.respond(&mut Registers([0; 8]), &mut buf).unwrap();
+        assert_eq!(&buf[..len], &[0x11, 0x8F, 0x03, 0x05, 0xF4]);
+    }
+
     #[tokio::test]
     async fn fn16() {
         let bb = BBBuffer::<U2048>::new();
@@ -428,4 +1080,1343 @@ mod tests {
             _ => panic!("Unexpected request result."),
         }
     }
+
+    #[tokio::test]
+    async fn fn22() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+
+        let data = [0x11, 0x16, 0x00, 0x04, 0x00, 0xF2, 0x00, 0x25, 0x66, 0xE2];
+
+        let address = 0x0004;
+        let and_mask = 0x00F2;
+        let or_mask = 0x0025;
+
+        modbus.on_data_received(&data);
+        assert_eq!(
+            modbus.next().await,
+            Ok(RequestFrame {
+                slave_id: 0x11,
+                request: Request::MaskWriteRegister {
+                    address,
+                    and_mask,
+                    or_mask
+                }
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn fn22_respond() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut registers = Registers([0; 8]);
+        registers.0[4] = 0x0012;
+        let mut buf = [0; 256];
+
+        let data = [0x11, 0x16, 0x00, 0x04, 0x00, 0xF2, 0x00, 0x25, 0x66, 0xE2];
+
+        modbus.on_data_received(&data);
+        let frame = modbus.next().await.unwrap();
+        let len = frame.respond(&mut registers, &mut buf);
+
+        // The response is an echo of the request.
+        assert_eq!(len, Some(data.len()));
+        assert_eq!(&buf[..data.len()], &data);
+        assert_eq!(registers.0[4], 0x0017);
+    }
+
+    #[tokio::test]
+    async fn respond_illegal_function() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut buf = [0; 256];
+
+        struct Empty;
+        impl Handler for Empty {}
+
+        let data = [0x11, 0x03, 0x00, 0x04, 0x00, 0x01, 0xC7, 0x5B];
+
+        modbus.on_data_received(&data);
+        let frame = modbus.next().await.unwrap();
+        let len = frame.respond(&mut Empty, &mut buf).unwrap();
+
+        assert_eq!(&buf[..len], &[0x11, 0x83, 0x01, 0x81, 0x35]);
+    }
+
+    #[tokio::test]
+    async fn fn23() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let data = [
+            0x11, 0x17, 0x00, 0x03, 0x00, 0x06, 0x00, 0x0E, 0x00, 0x03, 0x06, 0x00, 0xFF, 0x00,
+            0xFF, 0x00, 0xFF, 0x4B, 0x54,
+        ];
+
+        modbus.on_data_received(&data);
+        let frame = modbus.next().await;
+
+        match frame {
+            Ok(RequestFrame {
+                slave_id: 0x11,
+                request:
+                    Request::ReadWriteMultipleRegisters {
+                        read_address,
+                        read_count,
+                        write_address,
+                        write_count,
+                        registers,
+                    },
+            }) => {
+                assert_eq!(read_address, 0x0003);
+                assert_eq!(read_count, 0x0006);
+                assert_eq!(write_address, 0x000E);
+                assert_eq!(write_count, 0x0003);
+
+                let registers = registers.iter().collect::<Vec<_>>();
+                assert_eq!(registers, vec![0x00FF, 0x00FF, 0x00FF]);
+            }
+            _ => panic!("Unexpected request result."),
+        }
+    }
+
+    #[tokio::test]
+    async fn fn23_respond() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut registers = Registers([0; 8]);
+        let mut buf = [0; 256];
+
+        let data = [
+            0x11, 0x17, 0x00, 0x00, 0x00, 0x04, 0x00, 0x02, 0x00, 0x02, 0x04, 0x12, 0x34, 0x56,
+            0x78, 0xCD, 0xBE,
+        ];
+
+        modbus.on_data_received(&data);
+        let frame = modbus.next().await.unwrap();
+        let len = frame.respond(&mut registers, &mut buf).unwrap();
+
+        // The written registers are already contained in the read back values.
+        assert_eq!(
+            &buf[..len],
+            &[0x11, 0x17, 0x08, 0x00, 0x00, 0x00, 0x00, 0x12, 0x34, 0x56, 0x78, 0xFA, 0x63]
+        );
+    }
+
+    /// A handler which identifies itself with the given objects.
+    struct Identification(&'static [DeviceObject<'static>]);
+
+    impl Handler for Identification {
+        fn device_identification(&self) -> Option<DeviceIdentification<'_>> {
+            Some(DeviceIdentification::new(self.0))
+        }
+    }
+
+    #[tokio::test]
+    async fn fn43_basic() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut buf = [0; 256];
+        let mut identification = Identification(&[
+            DeviceObject {
+                id: object_id::VENDOR_NAME,
+                value: b"ACME",
+            },
+            DeviceObject {
+                id: object_id::PRODUCT_CODE,
+                value: b"P1",
+            },
+            DeviceObject {
+                id: object_id::MAJOR_MINOR_REVISION,
+                value: b"V1.0",
+            },
+            DeviceObject {
+                id: object_id::MODEL_NAME,
+                value: b"M",
+            },
+        ]);
+
+        let data = [0x11, 0x2B, 0x0E, 0x01, 0x00, 0xB1, 0xB4];
+
+        modbus.on_data_received(&data);
+        let frame = modbus.next().await.unwrap();
+        assert_eq!(
+            frame,
+            RequestFrame {
+                slave_id: 0x11,
+                request: Request::ReadDeviceIdentification {
+                    read_device_id_code: 0x01,
+                    object_id: 0x00
+                }
+            }
+        );
+
+        let len = frame.respond(&mut identification, &mut buf).unwrap();
+        assert_eq!(
+            &buf[..len],
+            &[
+                0x11, 0x2B, 0x0E, 0x01, 0x82, 0x00, 0x00, 0x03, 0x00, 0x04, 0x41, 0x43, 0x4D, 0x45,
+                0x01, 0x02, 0x50, 0x31, 0x02, 0x04, 0x56, 0x31, 0x2E, 0x30, 0x7C, 0x17
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fn43_more_follows() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut buf = [0; 256];
+        let mut identification = Identification(&[
+            DeviceObject {
+                id: object_id::VENDOR_NAME,
+                value: b"ACME",
+            },
+            DeviceObject {
+                id: object_id::PRODUCT_CODE,
+                value: b"P1",
+            },
+            DeviceObject {
+                id: object_id::MAJOR_MINOR_REVISION,
+                value: b"V1.0",
+            },
+            DeviceObject {
+                id: 0x80,
+                value: &[0xAA; 100],
+            },
+            DeviceObject {
+                id: 0x81,
+                value: &[0xBB; 100],
+            },
+            DeviceObject {
+                id: 0x82,
+                value: &[0xCC; 100],
+            },
+        ]);
+
+        // The last object does not fit into the first response anymore.
+        modbus.on_data_received(&[0x11, 0x2B, 0x0E, 0x03, 0x00, 0xB0, 0xD4]);
+        let frame = modbus.next().await.unwrap();
+        let len = frame.respond(&mut identification, &mut buf).unwrap();
+        assert_eq!(&buf[2..8], &[0x0E, 0x03, 0x83, 0xFF, 0x82, 0x05]);
+        assert_eq!(len, 8 + 16 + 2 * 102 + 2);
+        drop(frame);
+
+        modbus.on_data_received(&[0x11, 0x2B, 0x0E, 0x03, 0x82, 0x30, 0xB5]);
+        let frame = modbus.next().await.unwrap();
+        let len = frame.respond(&mut identification, &mut buf).unwrap();
+        assert_eq!(
+            &buf[2..10],
+            &[0x0E, 0x03, 0x83, 0x00, 0x00, 0x01, 0x82, 100]
+        );
+        assert_eq!(len, 10 + 100 + 2);
+    }
+
+    #[tokio::test]
+    async fn fn43_long_object() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut buf = [0; 256];
+
+        // An object too long for a single response, which `DeviceIdentification::new` rejects.
+        struct Unchecked(&'static [DeviceObject<'static>]);
+        impl Handler for Unchecked {
+            fn device_identification(&self) -> Option<DeviceIdentification<'_>> {
+                Some(DeviceIdentification { objects: self.0 })
+            }
+        }
+        let mut identification = Unchecked(&[
+            DeviceObject {
+                id: object_id::VENDOR_NAME,
+                value: b"ACME",
+            },
+            DeviceObject {
+                id: 0x80,
+                value: &[0xAA; 300],
+            },
+            DeviceObject {
+                id: 0x81,
+                value: b"X",
+            },
+        ]);
+
+        // The stream continues after the long object, whose value is truncated.
+        modbus.on_data_received(&[0x11, 0x2B, 0x0E, 0x03, 0x80, 0xB1, 0x74]);
+        let frame = modbus.next().await.unwrap();
+        let len = frame.respond(&mut identification, &mut buf).unwrap();
+        assert_eq!(
+            &buf[2..10],
+            &[0x0E, 0x03, 0x83, 0xFF, 0x81, 0x01, 0x80, 244]
+        );
+        assert_eq!(len, 256);
+    }
+
+    #[tokio::test]
+    async fn fn43_unsupported_mei_type() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut buf = [0; 256];
+
+        // CANopen general reference, whose frame ends with the idle line.
+        modbus.on_data_received(&[0x11, 0x2B, 0x0D, 0x00, 0x00, 0x40, 0x24]);
+        modbus.on_idle_line();
+        let frame = modbus.next().await.unwrap();
+        assert_eq!(
+            frame,
+            RequestFrame {
+                slave_id: 0x11,
+                request: Request::EncapsulatedInterfaceTransport { mei_type: 0x0D }
+            }
+        );
+
+        let len = frame.respond(&mut Identification(&[]), &mut buf).unwrap();
+        assert_eq!(&buf[..len], &[0x11, 0xAB, 0x01, 0x9F, 0x35]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn fn43_object_too_long() {
+        DeviceIdentification::new(&[DeviceObject {
+            id: object_id::VENDOR_NAME,
+            value: &[0xAA; DeviceObject::MAX_LEN + 1],
+        }]);
+    }
+
+    #[tokio::test]
+    async fn fn17() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut buf = [0; 256];
+
+        struct Server;
+        impl Handler for Server {
+            fn server_id(&self) -> Option<ServerId<'_>> {
+                Some(ServerId {
+                    id: b"MB10",
+                    running: true,
+                })
+            }
+        }
+
+        let data = [0x11, 0x11, 0xCD, 0xEC];
+
+        modbus.on_data_received(&data);
+        let frame = modbus.next().await.unwrap();
+        assert_eq!(
+            frame,
+            RequestFrame {
+                slave_id: 0x11,
+                request: Request::ReportServerId
+            }
+        );
+
+        let len = frame.respond(&mut Server, &mut buf).unwrap();
+        assert_eq!(
+            &buf[..len],
+            &[0x11, 0x11, 0x05, 0x4D, 0x42, 0x31, 0x30, 0xFF, 0xBC, 0x66]
+        );
+    }
+
+    #[tokio::test]
+    async fn fn17_id_too_long() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut buf = [0; 256];
+
+        struct Server(&'static [u8]);
+        impl Handler for Server {
+            fn server_id(&self) -> Option<ServerId<'_>> {
+                Some(ServerId {
+                    id: self.0,
+                    running: true,
+                })
+            }
+        }
+
+        // The longest id fills the whole response.
+        modbus.on_data_received(&[0x11, 0x11, 0xCD, 0xEC]);
+        let len = modbus
+            .serve(&mut Server(&[0xAA; ServerId::MAX_LEN]), &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(len, Some(256));
+        assert_eq!(buf[2], ServerId::MAX_LEN as u8 + 1);
+
+        modbus.on_data_received(&[0x11, 0x11, 0xCD, 0xEC]);
+        let len = modbus
+            .serve(&mut Server(&[0xAA; 255]), &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(len, Some(5));
+        assert_eq!(&buf[..5], &[0x11, 0x91, 0x04, 0x4D, 0x96]);
+    }
+
+    #[tokio::test]
+    async fn fn8_return_query_data() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut registers = Registers([0; 8]);
+        let mut buf = [0; 256];
+
+        let data = [0x11, 0x08, 0x00, 0x00, 0xA5, 0x37, 0xD8, 0x1D];
+
+        // The query data can have any length, so the frame is complete with the idle line only.
+        modbus.on_data_received(&data);
+        assert!(modbus.next().now_or_never().is_none());
+        modbus.on_idle_line();
+        match modbus.next().await {
+            Ok(RequestFrame {
+                slave_id: 0x11,
+                request: Request::ReturnQueryData { query },
+            }) => assert_eq!(query.bytes(), &[0x08, 0x00, 0x00, 0xA5, 0x37]),
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+
+        // The query data is echoed back.
+        modbus.on_data_received(&data);
+        modbus.on_idle_line();
+        let len = modbus.serve(&mut registers, &mut buf).await.unwrap();
+        assert_eq!(len, Some(data.len()));
+        assert_eq!(&buf[..data.len()], &data);
+
+        // An odd number of bytes in two chunks.
+        let data = [
+            0x11, 0x08, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0xC5, 0xBD,
+        ];
+        modbus.on_data_received(&data[..6]);
+        modbus.on_data_received(&data[6..]);
+        modbus.on_idle_line();
+        let len = modbus.serve(&mut registers, &mut buf).await.unwrap();
+        assert_eq!(len, Some(data.len()));
+        assert_eq!(&buf[..data.len()], &data);
+
+        // A frame too short to hold the sub-function and the CRC is dropped.
+        modbus.on_data_received(&[0x11, 0x08, 0x00, 0x00, 0x62]);
+        modbus.on_idle_line();
+        assert_eq!(modbus.next().await, Err(Error::InvalidFrame));
+    }
+
+    #[tokio::test]
+    async fn fn8_counters() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut registers = Registers([0; 8]);
+        let mut buf = [0; 256];
+
+        modbus.on_data_received(&[0x11, 0x03, 0x00, 0x04, 0x00, 0x01, 0xC7, 0x5B]);
+        assert!(modbus.serve(&mut registers, &mut buf).await.is_ok());
+        modbus.on_data_received(&[0x11, 0x03, 0x00, 0x04, 0x00, 0x01, 0xC7, 0x5C]);
+        assert_eq!(
+            modbus.serve(&mut registers, &mut buf).await,
+            Err(Error::Crc)
+        );
+
+        modbus.on_data_received(&[0x11, 0x08, 0x00, 0x0B, 0x00, 0x00, 0x93, 0x59]);
+        let len = modbus
+            .serve(&mut registers, &mut buf)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            &buf[..len],
+            &[0x11, 0x08, 0x00, 0x0B, 0x00, 0x03, 0xD3, 0x58]
+        );
+
+        modbus.on_data_received(&[0x11, 0x08, 0x00, 0x0C, 0x00, 0x00, 0x22, 0x98]);
+        let len = modbus
+            .serve(&mut registers, &mut buf)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            &buf[..len],
+            &[0x11, 0x08, 0x00, 0x0C, 0x00, 0x01, 0xE3, 0x58]
+        );
+    }
+
+    #[tokio::test]
+    async fn fn8_listen_only() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut registers = Registers([0; 8]);
+        let mut buf = [0; 256];
+        let read = [0x11, 0x03, 0x00, 0x04, 0x00, 0x01, 0xC7, 0x5B];
+
+        // Neither the switch to listen only mode nor any request afterwards is answered.
+        modbus.on_data_received(&[0x11, 0x08, 0x00, 0x04, 0x00, 0x00, 0xA3, 0x5A]);
+        assert_eq!(modbus.serve(&mut registers, &mut buf).await, Ok(None));
+        modbus.on_data_received(&read);
+        assert_eq!(modbus.serve(&mut registers, &mut buf).await, Ok(None));
+
+        // The restart brings the slave back.
+        modbus.on_data_received(&[0x11, 0x08, 0x00, 0x01, 0x00, 0x00, 0xB3, 0x5B]);
+        assert_eq!(modbus.serve(&mut registers, &mut buf).await, Ok(None));
+        modbus.on_data_received(&read);
+        assert_eq!(modbus.serve(&mut registers, &mut buf).await, Ok(Some(7)));
+    }
+
+    #[tokio::test]
+    async fn bus_counters() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+
+        modbus.on_data_received(&[0x11, 0x01, 0x00, 0x13, 0x00, 0x25, 0x0E, 0x84]);
+        assert!(modbus.next().await.is_ok());
+        modbus.on_data_received(&[0x11, 0x01, 0x00, 0x13, 0x00, 0x25, 0x0E, 0x85]);
+        assert_eq!(modbus.next().await, Err(Error::Crc));
+
+        let counters = modbus.counters();
+        assert_eq!(counters.bus_message, 2);
+        assert_eq!(counters.bus_communication_error, 1);
+
+        modbus.clear_counters();
+        assert_eq!(modbus.counters(), Counters::default());
+    }
+
+    #[test]
+    fn bus_character_overrun() {
+        let bb = BBBuffer::<U16>::new();
+        let mut modbus = super::Modbus::new(&bb);
+
+        // The received data does not fit into the buffer anymore.
+        modbus.on_data_received(&[0x11, 0x01, 0x00, 0x13, 0x00, 0x25, 0x0E, 0x84]);
+        modbus.on_data_received(&[0x11, 0x01, 0x00, 0x13, 0x00, 0x25, 0x0E, 0x84, 0x00]);
+        assert_eq!(modbus.counters().bus_character_overrun, 1);
+    }
+
+    #[tokio::test]
+    async fn listen_only() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut registers = Registers([0; 8]);
+        let mut buf = [0; 256];
+        let read = [0x11, 0x03, 0x00, 0x04, 0x00, 0x01, 0xC7, 0x5B];
+
+        modbus.set_listen_only(true);
+        assert!(modbus.is_listen_only());
+
+        modbus.on_data_received(&read);
+        assert_eq!(modbus.serve(&mut registers, &mut buf).await, Ok(None));
+        modbus.on_data_received(&[0x11, 0x08, 0x00, 0x00, 0xA5, 0x37, 0xD8, 0x1D]);
+        modbus.on_idle_line();
+        assert_eq!(modbus.serve(&mut registers, &mut buf).await, Ok(None));
+
+        // An invalid restart does not even get an exception response.
+        modbus.on_data_received(&[0x11, 0x08, 0x00, 0x01, 0x00, 0x01, 0x72, 0x9B]);
+        assert_eq!(modbus.serve(&mut registers, &mut buf).await, Ok(None));
+        assert!(modbus.is_listen_only());
+
+        let counters = modbus.counters();
+        assert_eq!(counters.server_message, 3);
+        assert_eq!(counters.server_no_response, 3);
+
+        modbus.set_listen_only(false);
+        modbus.on_data_received(&read);
+        assert_eq!(modbus.serve(&mut registers, &mut buf).await, Ok(Some(7)));
+    }
+
+    #[tokio::test]
+    async fn fn11_fn12() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut registers = Registers([0; 8]);
+        let mut buf = [0; 256];
+
+        // Only the successful read is counted as an event.
+        modbus.on_data_received(&[0x11, 0x03, 0x00, 0x04, 0x00, 0x01, 0xC7, 0x5B]);
+        assert!(modbus.serve(&mut registers, &mut buf).await.is_ok());
+        modbus.on_data_received(&[0x11, 0x03, 0x00, 0x10, 0x00, 0x01, 0x87, 0x5F]);
+        assert!(modbus.serve(&mut registers, &mut buf).await.is_ok());
+
+        modbus.on_data_received(&[0x11, 0x0B, 0x4C, 0x27]);
+        let len = modbus
+            .serve(&mut registers, &mut buf)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            &buf[..len],
+            &[0x11, 0x0B, 0x00, 0x00, 0x00, 0x01, 0x67, 0x5B]
+        );
+
+        // The events are reported with the most recent one first.
+        modbus.set_busy(true);
+        modbus.on_data_received(&[0x11, 0x0C, 0x0D, 0xE5]);
+        let len = modbus
+            .serve(&mut registers, &mut buf)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            &buf[..len],
+            &[
+                0x11, 0x0C, 0x0D, 0xFF, 0xFF, 0x00, 0x01, 0x00, 0x04, 0x80, 0x40, 0x80, 0x41, 0x80,
+                0x40, 0x80, 0x8A, 0xDB
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fn7() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut buf = [0; 256];
+
+        struct Alarms(u8);
+        impl Handler for Alarms {
+            fn read_exception_status(&mut self) -> Result<u8, Exception> {
+                Ok(self.0)
+            }
+        }
+
+        let data = [0x11, 0x07, 0x4C, 0x22];
+
+        modbus.on_data_received(&data);
+        let frame = modbus.next().await.unwrap();
+        assert_eq!(
+            frame,
+            RequestFrame {
+                slave_id: 0x11,
+                request: Request::ReadExceptionStatus
+            }
+        );
+
+        let len = frame.respond(&mut Alarms(0x6D), &mut buf).unwrap();
+        assert_eq!(&buf[..len], &[0x11, 0x07, 0x6D, 0xE2, 0x18]);
+    }
+
+    /// A file store with a single file number 4 of twenty records.
+    struct Files([u16; 20]);
+
+    impl Files {
+        fn check(&self, file_number: u16, record_number: u16, len: usize) -> Result<(), Exception> {
+            if file_number != 4 || record_number as usize + len / 2 > self.0.len() {
+                return Err(Exception::IllegalDataAddress);
+            }
+            Ok(())
+        }
+    }
+
+    impl FileStore for Files {
+        fn read_records(
+            &mut self,
+            file_number: u16,
+            record_number: u16,
+            data: &mut [u8],
+        ) -> Result<(), Exception> {
+            self.check(file_number, record_number, data.len())?;
+            for (i, chunk) in data.chunks_mut(2).enumerate() {
+                chunk.copy_from_slice(&self.0[record_number as usize + i].to_be_bytes());
+            }
+            Ok(())
+        }
+
+        fn write_records(
+            &mut self,
+            file_number: u16,
+            record_number: u16,
+            data: &[u8],
+        ) -> Result<(), Exception> {
+            self.check(file_number, record_number, data.len())?;
+            for (i, chunk) in data.chunks(2).enumerate() {
+                self.0[record_number as usize + i] = u16::from_be_bytes([chunk[0], chunk[1]]);
+            }
+            Ok(())
+        }
+    }
+
+    impl Handler for Files {
+        fn file_store(&mut self) -> Option<&mut dyn FileStore> {
+            Some(self)
+        }
+    }
+
+    #[tokio::test]
+    async fn fn20() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut buf = [0; 256];
+        let mut files = Files([0; 20]);
+        for (i, record) in files.0.iter_mut().enumerate() {
+            *record = 0x1000 + i as u16;
+        }
+
+        let data = [
+            0x11, 0x14, 0x0E, 0x06, 0x00, 0x04, 0x00, 0x01, 0x00, 0x02, 0x06, 0x00, 0x04, 0x00,
+            0x05, 0x00, 0x01, 0xCC, 0xFA,
+        ];
+
+        modbus.on_data_received(&data);
+        let frame = modbus.next().await.unwrap();
+        match &frame.request {
+            Request::ReadFileRecord { records } => {
+                let records = records
+                    .iter()
+                    .map(|r| (r.file_number, r.record_number, r.record_length))
+                    .collect::<Vec<_>>();
+                assert_eq!(records, vec![(4, 1, 2), (4, 5, 1)]);
+            }
+            request => panic!("unexpected request {:?}", request),
+        }
+
+        let len = frame.respond(&mut files, &mut buf).unwrap();
+        assert_eq!(
+            &buf[..len],
+            &[
+                0x11, 0x14, 0x0A, 0x05, 0x06, 0x10, 0x01, 0x10, 0x02, 0x03, 0x06, 0x10, 0x05, 0x52,
+                0xB8
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fn20_illegal_address() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut buf = [0; 256];
+
+        // File number 0 does not exist.
+        let data = [
+            0x11, 0x14, 0x07, 0x06, 0x00, 0x00, 0x00, 0x01, 0x00, 0x02, 0x28, 0xB0,
+        ];
+
+        modbus.on_data_received(&data);
+        let frame = modbus.next().await.unwrap();
+        let len = frame.respond(&mut Files([0; 20]), &mut buf).unwrap();
+        assert_eq!(&buf[..len], &[0x11, 0x94, 0x02, 0xCE, 0xC4]);
+    }
+
+    #[tokio::test]
+    async fn fn21() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut buf = [0; 256];
+        let mut files = Files([0; 20]);
+
+        let data = [
+            0x11, 0x15, 0x0D, 0x06, 0x00, 0x04, 0x00, 0x07, 0x00, 0x03, 0x06, 0xAF, 0x04, 0xBE,
+            0x10, 0x0D, 0xDB, 0xC7,
+        ];
+
+        modbus.on_data_received(&data);
+        let frame = modbus.next().await.unwrap();
+        let len = frame.respond(&mut files, &mut buf).unwrap();
+        // The response is an echo of the request.
+        assert_eq!(&buf[..len], &data[..]);
+        assert_eq!(&files.0[7..10], &[0x06AF, 0x04BE, 0x100D]);
+    }
+
+    /// A single FIFO queue at pointer address 0x04DE.
+    struct Fifo(&'static [u16]);
+
+    impl FifoSource for Fifo {
+        fn fifo_count(&mut self, pointer_address: u16) -> Result<u16, Exception> {
+            if pointer_address != 0x04DE {
+                return Err(Exception::IllegalDataAddress);
+            }
+            Ok(self.0.len() as u16)
+        }
+
+        fn read_fifo(&mut self, _pointer_address: u16, index: u16) -> Result<u16, Exception> {
+            Ok(self.0[index as usize])
+        }
+    }
+
+    impl Handler for Fifo {
+        fn fifo_source(&mut self) -> Option<&mut dyn FifoSource> {
+            Some(self)
+        }
+    }
+
+    #[tokio::test]
+    async fn fn24() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut buf = [0; 256];
+
+        let data = [0x11, 0x18, 0x04, 0xDE, 0x07, 0x87];
+
+        modbus.on_data_received(&data);
+        let frame = modbus.next().await.unwrap();
+        assert_eq!(
+            frame,
+            RequestFrame {
+                slave_id: 0x11,
+                request: Request::ReadFifoQueue {
+                    pointer_address: 0x04DE
+                }
+            }
+        );
+
+        let len = frame
+            .respond(&mut Fifo(&[0x01B8, 0x1284, 0x0007]), &mut buf)
+            .unwrap();
+        assert_eq!(
+            &buf[..len],
+            &[0x11, 0x18, 0x00, 0x08, 0x00, 0x03, 0x01, 0xB8, 0x12, 0x84, 0x00, 0x07, 0xD7, 0x07]
+        );
+    }
+
+    #[tokio::test]
+    async fn fn24_too_many() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut buf = [0; 256];
+
+        let data = [0x11, 0x18, 0x04, 0xDE, 0x07, 0x87];
+
+        modbus.on_data_received(&data);
+        let frame = modbus.next().await.unwrap();
+        let len = frame.respond(&mut Fifo(&[0; 32]), &mut buf).unwrap();
+        assert_eq!(&buf[..len], &[0x11, 0x98, 0x03, 0x0A, 0x04]);
+    }
+
+    #[tokio::test]
+    async fn custom_function() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut buf = [0; 256];
+
+        /// Answers with the request data in reverse order.
+        struct Calibration;
+        impl Handler for Calibration {
+            fn custom_function(
+                &mut self,
+                pdu: &[u8],
+                response: &mut [u8],
+            ) -> Result<usize, Exception> {
+                let data = &pdu[2..];
+                for (r, d) in response.iter_mut().zip(data.iter().rev()) {
+                    *r = *d;
+                }
+                Ok(data.len())
+            }
+        }
+
+        assert_eq!(
+            modbus.register_function(0x03, |_| Some(8)),
+            Err(Error::ReservedFunction(0x03))
+        );
+        // The byte count follows the function code.
+        modbus
+            .register_function(0x41, |data| data.get(2).map(|&n| 5 + n as usize))
+            .unwrap();
+
+        let data = [0x11, 0x41, 0x02, 0x12, 0x34, 0x60, 0x88];
+
+        modbus.on_data_received(&data);
+        let frame = modbus.next().await.unwrap();
+        match &frame.request {
+            Request::Custom { pdu } => assert_eq!(pdu.bytes(), &[0x41, 0x02, 0x12, 0x34]),
+            request => panic!("unexpected request {:?}", request),
+        }
+
+        let len = frame.respond(&mut Calibration, &mut buf).unwrap();
+        assert_eq!(&buf[..len], &[0x11, 0x41, 0x34, 0x12, 0xC3, 0xC1]);
+    }
+
+    #[tokio::test]
+    async fn custom_function_invalid_len() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut buf = [0; 256];
+
+        /// Claims a response longer than a frame.
+        struct Oversized;
+        impl Handler for Oversized {
+            fn custom_function(&mut self, _: &[u8], _: &mut [u8]) -> Result<usize, Exception> {
+                Ok(300)
+            }
+        }
+
+        // A request shorter than the slave id, function code and CRC is dropped.
+        modbus.register_function(0x42, |_| Some(2)).unwrap();
+        modbus.on_data_received(&[0x11, 0x42, 0x00]);
+        modbus.on_idle_line();
+        assert_eq!(modbus.next().await, Err(Error::InvalidFrame));
+
+        modbus.register_function(0x43, |_| Some(4)).unwrap();
+        modbus.on_data_received(&[0x11, 0x43, 0x4C, 0x11]);
+        let frame = modbus.next().await.unwrap();
+        let len = frame.respond(&mut Oversized, &mut buf).unwrap();
+        assert_eq!(&buf[..len], &[0x11, 0xC3, 0x04, 0x70, 0xF6]);
+    }
+
+    /// An Enron Modbus device with 32 bit registers and an event log.
+    #[derive(Default)]
+    struct FlowComputer {
+        long_registers: Vec<(u16, u32)>,
+        registers: Vec<(u16, u16)>,
+        events: Vec<u8>,
+    }
+
+    impl Handler for FlowComputer {
+        fn read_long_register(&mut self, address: u16) -> Result<u32, Exception> {
+            Ok(0x0001_0000 + address as u32)
+        }
+
+        fn set_long_register(&mut self, address: u16, value: u32) -> Result<(), Exception> {
+            self.long_registers.push((address, value));
+            Ok(())
+        }
+
+        fn set_register(&mut self, address: u16, value: u16) -> Result<(), Exception> {
+            self.registers.push((address, value));
+            Ok(())
+        }
+
+        fn read_event_log(&mut self, records: &mut [u8]) -> Result<usize, Exception> {
+            records[..self.events.len()].copy_from_slice(&self.events);
+            Ok(self.events.len())
+        }
+
+        fn acknowledge_event_log(&mut self) -> Result<(), Exception> {
+            self.events.clear();
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn enron_long_registers() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        modbus.set_enron(Enron::new(&ENRON_LONG_REGISTERS));
+        let mut buf = [0; 256];
+        let mut handler = FlowComputer::default();
+
+        {
+            let data = [0x11, 0x03, 0x13, 0x88, 0x00, 0x02, 0x42, 0x35];
+            modbus.on_data_received(&data);
+            let frame = modbus.next().await.unwrap();
+            assert_eq!(
+                frame,
+                RequestFrame {
+                    slave_id: 0x11,
+                    request: Request::ReadLongRegisters {
+                        address: 5000,
+                        count: 2
+                    }
+                }
+            );
+            let len = frame.respond(&mut handler, &mut buf).unwrap();
+            assert_eq!(
+                &buf[..len],
+                &[0x11, 0x03, 0x08, 0x00, 0x01, 0x13, 0x88, 0x00, 0x01, 0x13, 0x89, 0xAE, 0x3D]
+            );
+        }
+
+        // Writing a single 32 bit register takes a four byte value.
+        let data = [0x11, 0x06, 0x1B, 0x58, 0x3F, 0x80, 0x00, 0x00, 0xC9, 0x11];
+        modbus.on_data_received(&data);
+        let len = modbus.serve(&mut handler, &mut buf).await.unwrap().unwrap();
+        assert_eq!(&buf[..len], &data[..]);
+
+        // Registers outside of the long register ranges keep their size.
+        let data = [0x11, 0x06, 0x00, 0x64, 0x00, 0x2A, 0x4B, 0x5A];
+        modbus.on_data_received(&data);
+        let len = modbus.serve(&mut handler, &mut buf).await.unwrap().unwrap();
+        assert_eq!(&buf[..len], &data[..]);
+
+        let data = [
+            0x11, 0x10, 0x13, 0x88, 0x00, 0x01, 0x04, 0x12, 0x34, 0x56, 0x78, 0x0C, 0x9E,
+        ];
+        modbus.on_data_received(&data);
+        let len = modbus.serve(&mut handler, &mut buf).await.unwrap().unwrap();
+        assert_eq!(
+            &buf[..len],
+            &[0x11, 0x10, 0x13, 0x88, 0x00, 0x01, 0x87, 0xF7]
+        );
+
+        assert_eq!(
+            handler.long_registers,
+            vec![(7000, 0x3F80_0000), (5000, 0x1234_5678)]
+        );
+        assert_eq!(handler.registers, vec![(100, 42)]);
+    }
+
+    #[tokio::test]
+    async fn enron_event_log() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        modbus.set_enron(Enron::new(&ENRON_LONG_REGISTERS));
+        let mut buf = [0; 256];
+        let mut handler = FlowComputer {
+            events: vec![0xAA, 0xBB, 0xCC, 0xDD],
+            ..Default::default()
+        };
+
+        let data = [0x11, 0x03, 0x00, 0x20, 0x00, 0x01, 0x87, 0x50];
+        modbus.on_data_received(&data);
+        let len = modbus.serve(&mut handler, &mut buf).await.unwrap().unwrap();
+        assert_eq!(
+            &buf[..len],
+            &[0x11, 0x03, 0x04, 0xAA, 0xBB, 0xCC, 0xDD, 0x2F, 0x56]
+        );
+
+        let data = [0x11, 0x05, 0x00, 0x20, 0xFF, 0x00, 0x8F, 0x60];
+        modbus.on_data_received(&data);
+        let frame = modbus.next().await.unwrap();
+        assert_eq!(
+            frame,
+            RequestFrame {
+                slave_id: 0x11,
+                request: Request::AcknowledgeEventLog { value: 0xFF00 }
+            }
+        );
+        let len = frame.respond(&mut handler, &mut buf).unwrap();
+        assert_eq!(&buf[..len], &data[..]);
+        assert!(handler.events.is_empty());
+        drop(frame);
+
+        // The response echoes the value written.
+        let data = [0x11, 0x05, 0x00, 0x20, 0x00, 0x00, 0xCE, 0x90];
+        modbus.on_data_received(&data);
+        let len = modbus.serve(&mut handler, &mut buf).await.unwrap().unwrap();
+        assert_eq!(&buf[..len], &data[..]);
+    }
+
+    #[tokio::test]
+    async fn enron_event_log_too_long() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        modbus.set_enron(Enron::new(&ENRON_LONG_REGISTERS));
+        let mut buf = [0; 256];
+
+        // A handler claiming more events than fit into the response.
+        struct Overflow;
+        impl Handler for Overflow {
+            fn read_event_log(&mut self, _records: &mut [u8]) -> Result<usize, Exception> {
+                Ok(300)
+            }
+        }
+
+        modbus.on_data_received(&[0x11, 0x03, 0x00, 0x20, 0x00, 0x01, 0x87, 0x50]);
+        let len = modbus
+            .serve(&mut Overflow, &mut buf)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..len], &[0x11, 0x83, 0x04, 0x41, 0x36]);
+    }
+
+    #[tokio::test]
+    async fn echo_suppression() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        modbus.set_echo_suppression(true);
+
+        let response = [0x11, 0x03, 0x02, 0x00, 0x2A, 0xF8, 0x58];
+        let request = [0x11, 0x06, 0x00, 0x01, 0x00, 0x03, 0x9A, 0x9B];
+        modbus.on_data_transmitted(&response);
+
+        // The echo may arrive in pieces and be followed by the next request right away.
+        modbus.on_data_received(&response[..3]);
+        let mut data = response[3..].to_vec();
+        data.extend_from_slice(&request);
+        modbus.on_data_received(&data);
+
+        let frame = modbus.next().await.unwrap();
+        assert_eq!(
+            frame,
+            RequestFrame {
+                slave_id: 0x11,
+                request: Request::SetRegister {
+                    address: 1,
+                    value: 3
+                }
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn bus_collision() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        modbus.set_echo_suppression(true);
+
+        modbus.on_data_transmitted(&[0x11, 0x03, 0x02, 0x00, 0x2A, 0xF8, 0x58]);
+        modbus.on_data_received(&[0x11, 0x03, 0x02, 0xFF, 0x2A, 0xF8, 0x58]);
+        assert_eq!(modbus.next().await, Err(Error::BusCollision));
+
+        // The bus is usable again afterwards.
+        modbus.on_data_received(&[0x11, 0x06, 0x00, 0x01, 0x00, 0x03, 0x9A, 0x9B]);
+        assert!(modbus.next().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn dma_receive() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        modbus.set_echo_suppression(true);
+
+        let response = [0x11, 0x03, 0x02, 0x00, 0x2A, 0xF8, 0x58];
+        let request = [0x11, 0x06, 0x00, 0x01, 0x00, 0x03, 0x9A, 0x9B];
+        modbus.on_data_transmitted(&response);
+
+        // The DMA engine receives the echo and the request right into the grant.
+        let mut wgr = modbus.receive_grant(64).unwrap();
+        wgr[..7].copy_from_slice(&response);
+        wgr[7..15].copy_from_slice(&request);
+        modbus.commit_received(wgr, 15);
+        modbus.on_idle_line();
+
+        let frame = modbus.next().await.unwrap();
+        assert_eq!(
+            frame,
+            RequestFrame {
+                slave_id: 0x11,
+                request: Request::SetRegister {
+                    address: 1,
+                    value: 3
+                }
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn idle_line_truncated_frame() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let request = [0x11, 0x06, 0x00, 0x01, 0x00, 0x03, 0x9A, 0x9B];
+
+        // The line goes idle in the middle of a frame.
+        let mut wgr = modbus.receive_grant(64).unwrap();
+        wgr[..5].copy_from_slice(&request[..5]);
+        modbus.commit_received(wgr, 5);
+        modbus.on_idle_line();
+        assert_eq!(modbus.next().await, Err(Error::InvalidFrame));
+
+        // The next frame is received from its start.
+        let mut wgr = modbus.receive_grant(64).unwrap();
+        wgr[..8].copy_from_slice(&request);
+        modbus.commit_received(wgr, 8);
+        modbus.on_idle_line();
+        assert!(modbus.next().await.is_ok());
+        assert_eq!(modbus.counters().bus_message, 2);
+        assert_eq!(modbus.counters().bus_communication_error, 0);
+    }
+
+    #[tokio::test]
+    async fn receive_error() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let request = [0x11, 0x06, 0x00, 0x01, 0x00, 0x03, 0x9A, 0x9B];
+
+        // The UART reports an error in the middle of a frame.
+        modbus.on_data_received(&request[..5]);
+        modbus.on_receive_error();
+        assert_eq!(modbus.next().await, Err(Error::InvalidFrame));
+        assert_eq!(modbus.counters().bus_character_overrun, 1);
+
+        modbus.on_data_received(&request);
+        assert!(modbus.next().await.is_ok());
+        assert_eq!(modbus.counters().bus_communication_error, 0);
+    }
+
+    #[tokio::test]
+    async fn idle_line_unknown_function() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+
+        modbus.on_data_received(&[0x11, 0x30, 0x00, 0x01, 0x12, 0x34]);
+        modbus.on_idle_line();
+        assert_eq!(modbus.next().await, Err(Error::UnknownFunction(0x30)));
+
+        modbus.on_data_received(&[0x11, 0x06, 0x00, 0x01, 0x00, 0x03, 0x9A, 0x9B]);
+        assert!(modbus.next().await.is_ok());
+    }
+
+    #[test]
+    fn receive_grant_overrun() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+
+        assert!(modbus.receive_grant(4096).is_none());
+        assert_eq!(modbus.counters().bus_character_overrun, 1);
+    }
+
+    #[tokio::test]
+    async fn receive_grant_wraparound() {
+        let bb = BBBuffer::<U32>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let request = [0x11, 0x06, 0x00, 0x01, 0x00, 0x03, 0x9A, 0x9B];
+
+        // Two requests move the end of the received data towards the end of the buffer.
+        for _ in 0..2 {
+            modbus.on_data_received(&request);
+            assert!(modbus.next().await.is_ok());
+        }
+        // A request followed by the start of the next one, which ends 3 bytes before the end of the buffer.
+        let mut data = request.to_vec();
+        data.extend_from_slice(&request[..5]);
+        modbus.on_data_received(&data);
+        assert!(modbus.next().await.is_ok());
+
+        // The DMA grant for the rest of the request wraps around.
+        let mut wgr = modbus.receive_grant(8).unwrap();
+        wgr[..3].copy_from_slice(&request[5..]);
+        modbus.commit_received(wgr, 3);
+        modbus.on_idle_line();
+        assert_eq!(
+            modbus.next().await,
+            Ok(RequestFrame {
+                slave_id: 0x11,
+                request: Request::SetRegister {
+                    address: 1,
+                    value: 3
+                }
+            })
+        );
+
+        // Copying received data across the end of the buffer works as well.
+        for _ in 0..2 {
+            modbus.on_data_received(&request);
+            assert!(modbus.next().await.is_ok());
+        }
+        modbus.on_data_received(&request[..5]);
+        let mut data = request[5..].to_vec();
+        data.extend_from_slice(&request);
+        modbus.on_data_received(&data);
+        assert!(modbus.next().await.is_ok());
+        assert!(modbus.next().await.is_ok());
+        assert_eq!(modbus.counters().bus_communication_error, 0);
+    }
+
+    #[tokio::test]
+    async fn serve_queued() {
+        use core::{
+            future::Future,
+            task::{Context, Poll},
+        };
+
+        let bb = BBBuffer::<U2048>::new();
+        let tx = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        let mut registers = Registers([0, 42, 0, 0, 0, 0, 0, 0]);
+
+        // Read holding register 1, which stays received until a transmit buffer is set.
+        let request = [0x11, 0x03, 0x00, 0x01, 0x00, 0x01, 0xD7, 0x5A];
+        modbus.on_data_received(&request);
+        assert_eq!(
+            modbus.serve_queued(&mut registers).await,
+            Err(Error::NoTransmitBuffer)
+        );
+        modbus.set_transmit_buffer(&tx);
+        assert_eq!(modbus.serve_queued(&mut registers).await, Ok(Some(7)));
+
+        // The transmit interrupt sends the response in two steps.
+        let rgr = modbus.transmit_grant().unwrap();
+        assert_eq!(&rgr[..], &[0x11, 0x03, 0x02, 0x00, 0x2A, 0xF8, 0x58]);
+        modbus.on_data_sent(rgr, 4);
+        {
+            let transmitted = modbus.transmitted();
+            futures::pin_mut!(transmitted);
+            let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+            assert_eq!(transmitted.poll(&mut cx), Poll::Pending);
+        }
+        let rgr = modbus.transmit_grant().unwrap();
+        assert_eq!(&rgr[..], &[0x2A, 0xF8, 0x58]);
+        modbus.on_data_sent(rgr, 3);
+        modbus.transmitted().await;
+        assert!(modbus.transmit_grant().is_none());
+
+        // A broadcast is not answered, so nothing is queued.
+        modbus.on_data_received(&[0x00, 0x06, 0x00, 0x01, 0x00, 0x03, 0x99, 0xDA]);
+        assert_eq!(modbus.serve_queued(&mut registers).await, Ok(None));
+        assert!(modbus.transmit_grant().is_none());
+    }
+
+    #[tokio::test]
+    async fn strict_timing() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        modbus.set_bus_timing(BusTiming::new(9600, 11));
+        modbus.set_strict_timing(true);
+        let request = [0x11, 0x06, 0x00, 0x01, 0x00, 0x03, 0x9A, 0x9B];
+
+        // The request is received in two halves with a gap of two characters in between.
+        modbus.on_data_received_at(&request[..4], 10_000);
+        modbus.on_data_received_at(&request[4..], 10_000 + 4 * 1145 + 2290);
+        // It is followed by the same request after a proper frame gap.
+        modbus.on_data_received_at(&request, 10_000 + 12 * 1145 + 2290 + 5000);
+
+        assert_eq!(modbus.next().await, Err(Error::InvalidFrame));
+        assert!(modbus.next().await.is_ok());
+        assert_eq!(
+            modbus.timing_stats(),
+            TimingStats {
+                max_character_gap: 2290,
+                t1_5_violations: 1,
+                min_frame_gap: Some(5000),
+                max_frame_gap: Some(5000),
+            }
+        );
+    }
+
+    /// A CRC unit which logs the number of bytes fed at once.
+    #[derive(Default)]
+    struct CrcUnit {
+        state: SoftwareCrc,
+        updates: Vec<usize>,
+    }
+
+    impl Crc16 for CrcUnit {
+        fn reset(&mut self) {
+            self.state.reset();
+        }
+
+        fn update(&mut self, data: &[u8]) {
+            self.state.update(data);
+            self.updates.push(data.len());
+        }
+
+        fn get(&self) -> u16 {
+            self.state.get()
+        }
+    }
+
+    #[tokio::test]
+    async fn incremental_crc() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut crc = CrcUnit::default();
+        {
+            let mut modbus = super::Modbus::new(&bb);
+            modbus.set_crc(&mut crc);
+            // The receiver can still be moved to another task or thread.
+            fn assert_send<T: Send>(_: &T) {}
+            assert_send(&modbus);
+
+            // The first request arrives in two chunks, the second one also holds
+            // the next request with a broken CRC.
+            let request = [0x11, 0x06, 0x00, 0x01, 0x00, 0x03, 0x9A, 0x9B];
+            modbus.on_data_received(&request[..3]);
+            let mut data = request[3..].to_vec();
+            data.extend_from_slice(&request[..7]);
+            data.push(0x9C);
+            modbus.on_data_received(&data);
+
+            assert!(modbus.next().await.is_ok());
+            assert_eq!(modbus.next().await, Err(Error::Crc));
+        }
+        // The CRC of the first request is computed as it arrives. The one of the second request is computed
+        // over the whole frame once it is parsed, as the CRC unit was busy with the first request before.
+        assert_eq!(crc.updates, [3, 5, 8]);
+    }
+
+    #[tokio::test]
+    async fn strict_timing_buffered_frames() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = super::Modbus::new(&bb);
+        modbus.set_bus_timing(BusTiming::new(9600, 11));
+        modbus.set_strict_timing(true);
+        let request = [0x11, 0x06, 0x00, 0x01, 0x00, 0x03, 0x9A, 0x9B];
+
+        // A valid request, followed by one with a gap of two characters,
+        // both received before the first one is taken.
+        modbus.on_data_received_at(&request, 10_000);
+        modbus.on_data_received_at(&request[..4], 10_000 + 4 * 1145 + 5000);
+        modbus.on_data_received_at(&request[4..], 10_000 + 8 * 1145 + 5000 + 2290);
+
+        assert!(modbus.next().await.is_ok());
+        assert_eq!(modbus.next().await, Err(Error::InvalidFrame));
+    }
 }
@@ -1,8 +1,9 @@
 use crate::{
     consts,
-    data::{CoilState, CoilStore, RegisterStore},
+    data::{CoilState, CoilStore, FileRecordStore, Pdu, RegisterStore},
+    diagnostics::sub_function,
+    enron::{self, Enron},
     error::Error,
-    general,
 };
 use bbqueue::{ArrayLength, AutoReleaseGrantR};
 use core::convert::TryInto;
@@ -15,16 +16,21 @@ pub struct RequestFrame<'a, S: ArrayLength<u8>> {
 
 impl<'a, S: ArrayLength<u8>> RequestFrame<'a, S> {
     /// Parses a single modbus RTU request frame.
+    ///
+    /// `crc_valid` is the outcome of the CRC check, which the receiver computes as the bytes of the frame arrive,
+    /// or as the frame is taken if it was received together with the frame before it.
+    /// Requests to 32 bit registers and the event log are only recognized if `enron` is given.
     pub(crate) fn parse_frame(
         mut rgr: AutoReleaseGrantR<'a, S>,
         frame_len: usize,
+        crc_valid: bool,
+        enron: Option<&Enron>,
     ) -> Result<RequestFrame<'a, S>, Error> {
         // Make sure we mark the right amount of bytes as read in our read buffer.
         rgr.to_release(frame_len);
 
         // Make sure the received CRC is valid.
         // If it is not valid, immediately return an error.
-        let crc_valid = general::crc_valid(&rgr[..frame_len]);
         if !crc_valid {
             return Err(Error::Crc);
         }
@@ -48,7 +54,13 @@ impl<'a, S: ArrayLength<u8>> RequestFrame<'a, S> {
             }
             3 => {
                 let (address, count) = Self::parse_read_request(data);
-                Request::ReadOutputRegisters { address, count }
+                match enron {
+                    Some(_) if address == enron::EVENT_LOG_ADDRESS => Request::ReadEventLog,
+                    Some(enron) if enron.is_long(address) => {
+                        Request::ReadLongRegisters { address, count }
+                    }
+                    _ => Request::ReadOutputRegisters { address, count },
+                }
             }
             4 => {
                 let (address, count) = Self::parse_read_request(data);
@@ -58,20 +70,45 @@ impl<'a, S: ArrayLength<u8>> RequestFrame<'a, S> {
                 // We use `parse_read_request` even tho it's not the classic read request.
                 // But the function code 0x05 has the same format.
                 let (address, status) = Self::parse_read_request(data);
-                Request::SetCoil {
-                    address,
-                    status: if status == 0xFF00 {
-                        CoilState::On
-                    } else {
-                        // We assume that if the status was not 0xFF00 it was 0x0000 as expected.
-                        CoilState::Off
-                    },
+                if enron.is_some() && address == enron::EVENT_LOG_ADDRESS {
+                    Request::AcknowledgeEventLog { value: status }
+                } else {
+                    Request::SetCoil {
+                        address,
+                        status: if status == 0xFF00 {
+                            CoilState::On
+                        } else {
+                            // We assume that if the status was not 0xFF00 it was 0x0000 as expected.
+                            CoilState::Off
+                        },
+                    }
                 }
             }
             6 => {
                 let (address, value) = Self::parse_read_request(data);
-                Request::SetRegister { address, value }
+                match enron {
+                    Some(enron) if enron.is_long(address) => Request::SetLongRegister {
+                        address,
+                        value: u32::from_be_bytes(
+                            data[2..6].try_into().unwrap_or_else(|_| panic!()),
+                        ),
+                    },
+                    _ => Request::SetRegister { address, value },
+                }
             }
+            7 => Request::ReadExceptionStatus,
+            8 => {
+                let (sub_function, data) = Self::parse_read_request(data);
+                if sub_function == sub_function::RETURN_QUERY_DATA {
+                    Request::ReturnQueryData {
+                        query: Pdu::new(rgr, frame_len),
+                    }
+                } else {
+                    Request::Diagnostics { sub_function, data }
+                }
+            }
+            11 => Request::GetCommEventCounter,
+            12 => Request::GetCommEventLog,
             15 => {
                 let (address, count) = Self::parse_read_request(data);
                 Request::SetCoils {
@@ -82,12 +119,63 @@ impl<'a, S: ArrayLength<u8>> RequestFrame<'a, S> {
             }
             16 => {
                 let (address, count) = Self::parse_read_request(data);
-                Request::SetRegisters {
+                match enron {
+                    Some(enron) if enron.is_long(address) => Request::SetLongRegisters {
+                        address,
+                        count,
+                        registers: RegisterStore::new(rgr, 7),
+                    },
+                    _ => Request::SetRegisters {
+                        address,
+                        count,
+                        registers: RegisterStore::new(rgr, 7),
+                    },
+                }
+            }
+            17 => Request::ReportServerId,
+            20 => Request::ReadFileRecord {
+                records: FileRecordStore::new(rgr, false),
+            },
+            21 => Request::WriteFileRecord {
+                records: FileRecordStore::new(rgr, true),
+            },
+            22 => {
+                let (address, and_mask) = Self::parse_read_request(data);
+                let or_mask =
+                    u16::from_be_bytes(data[4..6].try_into().unwrap_or_else(|_| panic!()));
+                Request::MaskWriteRegister {
                     address,
-                    count,
-                    registers: RegisterStore::new(rgr),
+                    and_mask,
+                    or_mask,
+                }
+            }
+            23 => {
+                let (read_address, read_count) = Self::parse_read_request(data);
+                let (write_address, write_count) = Self::parse_read_request(&data[4..]);
+                Request::ReadWriteMultipleRegisters {
+                    read_address,
+                    read_count,
+                    write_address,
+                    write_count,
+                    registers: RegisterStore::new(rgr, 11),
                 }
             }
+            24 => Request::ReadFifoQueue {
+                pointer_address: u16::from_be_bytes(
+                    data[0..2].try_into().unwrap_or_else(|_| panic!()),
+                ),
+            },
+            43 if data[0] == consts::READ_DEVICE_IDENTIFICATION => {
+                Request::ReadDeviceIdentification {
+                    read_device_id_code: data[1],
+                    object_id: data[2],
+                }
+            }
+            43 => Request::EncapsulatedInterfaceTransport { mei_type: data[0] },
+            // Only registered user-defined functions make it past `parse_request_len`.
+            65..=72 | 100..=110 => Request::Custom {
+                pdu: Pdu::new(rgr, frame_len),
+            },
             f => return Err(Error::UnknownFunction(f)),
         };
 
@@ -98,9 +186,15 @@ impl<'a, S: ArrayLength<u8>> RequestFrame<'a, S> {
     }
 
     /// Returns the complete length of a request dataframe including slave ID and CRC.
-    /// The returned Result is always Ok except if the function code was unknown.
+    /// The returned Result is always Ok except if the function code was unknown or the frame invalid.
     /// If there was not enough databytes received yet, Ok(None) is returned.
-    pub(crate) fn parse_request_len(data: &[u8]) -> Result<Option<usize>, Error> {
+    /// `idle` tells whether the line went idle after `data`, which ends frames of a length not encoded in them.
+    pub(crate) fn parse_request_len(
+        data: &[u8],
+        custom_functions: &CustomFunctions,
+        enron: Option<&Enron>,
+        idle: bool,
+    ) -> Result<Option<usize>, Error> {
         // If the packet is not at least two bytes long, we cannot determine the function code
         // as well as the packet length, so we instanly return None, signaling that we await more bytes.
         if data.len() < 2 {
@@ -108,7 +202,39 @@ impl<'a, S: ArrayLength<u8>> RequestFrame<'a, S> {
         }
         let fn_code = data[1];
         Ok(match fn_code {
+            // Writing a 32 bit register takes a four byte value, so the length depends on the address.
+            consts::SET_REGISTER if enron.is_some() => {
+                if data.len() > 3 {
+                    let address = u16::from_be_bytes([data[2], data[3]]);
+                    Some(match enron {
+                        Some(enron) if enron.is_long(address) => 10,
+                        _ => 8,
+                    })
+                } else {
+                    // incomplete frame
+                    None
+                }
+            }
             consts::READ_COIL..=consts::SET_REGISTER => Some(8),
+            consts::DIAGNOSTICS => {
+                if data.len() > 3 {
+                    let sub_function = u16::from_be_bytes([data[2], data[3]]);
+                    if sub_function != sub_function::RETURN_QUERY_DATA {
+                        // All other sub-functions of a serial line take a single data word.
+                        Some(8)
+                    } else if !idle {
+                        // The query data can have any length, so only the idle line ends the frame.
+                        None
+                    } else if (6..=consts::MAX_FRAME_LEN).contains(&data.len()) {
+                        Some(data.len())
+                    } else {
+                        return Err(Error::InvalidFrame);
+                    }
+                } else {
+                    // incomplete frame
+                    None
+                }
+            }
             consts::SET_COILS | consts::SET_REGISTERS => {
                 if data.len() > 6 {
                     Some(9 + data[6] as usize)
@@ -117,15 +243,61 @@ impl<'a, S: ArrayLength<u8>> RequestFrame<'a, S> {
                     None
                 }
             }
-            _ => {
-                return Err(Error::UnknownFunction(fn_code));
+            consts::READ_EXCEPTION_STATUS
+            | consts::GET_COMM_EVENT_COUNTER
+            | consts::GET_COMM_EVENT_LOG
+            | consts::REPORT_SERVER_ID => Some(4),
+            consts::READ_FILE_RECORD | consts::WRITE_FILE_RECORD => {
+                if data.len() > 2 {
+                    Some(5 + data[2] as usize)
+                } else {
+                    // incomplete frame
+                    None
+                }
+            }
+            consts::MASK_WRITE_REGISTER => Some(10),
+            consts::READ_WRITE_REGISTERS => {
+                if data.len() > 10 {
+                    Some(13 + data[10] as usize)
+                } else {
+                    // incomplete frame
+                    None
+                }
             }
+            consts::READ_FIFO_QUEUE => Some(6),
+            consts::ENCAPSULATED_INTERFACE_TRANSPORT => {
+                if data.len() > 2 {
+                    if data[2] == consts::READ_DEVICE_IDENTIFICATION {
+                        Some(7)
+                    } else if !idle {
+                        // The length of other MEI types is unknown, so only the idle line ends the frame.
+                        None
+                    } else if (5..=consts::MAX_FRAME_LEN).contains(&data.len()) {
+                        Some(data.len())
+                    } else {
+                        return Err(Error::InvalidFrame);
+                    }
+                } else {
+                    // incomplete frame
+                    None
+                }
+            }
+            _ => match custom_functions.request_len(fn_code) {
+                // A frame holds at least the slave id, the function code and the CRC.
+                Some(request_len) => match request_len(data) {
+                    Some(len) if !(4..=consts::MAX_FRAME_LEN).contains(&len) => {
+                        return Err(Error::InvalidFrame)
+                    }
+                    len => len,
+                },
+                None => return Err(Error::UnknownFunction(fn_code)),
+            },
         })
     }
 
     // Parses the requests for fucntion IDs 1-6.
     // Those 6 requests all share the same (u16, u16) layout which is parsed by this function.
-    fn parse_read_request<'b>(data: &'b [u8]) -> (u16, u16) {
+    fn parse_read_request(data: &[u8]) -> (u16, u16) {
         // The next two unwraps don't increase binary size apparently.
         let address = u16::from_be_bytes(data[0..2].try_into().unwrap_or_else(|_| panic!()));
         let count = u16::from_be_bytes(data[2..4].try_into().unwrap_or_else(|_| panic!()));
@@ -134,6 +306,46 @@ impl<'a, S: ArrayLength<u8>> RequestFrame<'a, S> {
     }
 }
 
+/// Determines the length of a request to a user-defined function.
+///
+/// It is called with the bytes received so far, starting with the slave id,
+/// and returns the complete length of the request including slave id and CRC.
+/// If not enough bytes were received yet to know the length, `None` is returned.
+/// A length outside of 4 to 256 bytes makes the request an `Error::InvalidFrame`.
+pub type RequestLen = fn(&[u8]) -> Option<usize>;
+
+/// The user-defined functions registered with a receiver.
+pub(crate) struct CustomFunctions([Option<RequestLen>; 19]);
+
+impl CustomFunctions {
+    pub(crate) fn new() -> CustomFunctions {
+        CustomFunctions([None; 19])
+    }
+
+    /// Returns the slot of a function code reserved for user-defined functions, 65-72 and 100-110.
+    fn index(function_code: u8) -> Option<usize> {
+        match function_code {
+            65..=72 => Some(function_code as usize - 65),
+            100..=110 => Some(function_code as usize - 100 + 8),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn register(
+        &mut self,
+        function_code: u8,
+        request_len: RequestLen,
+    ) -> Result<(), Error> {
+        let index = Self::index(function_code).ok_or(Error::ReservedFunction(function_code))?;
+        self.0[index] = Some(request_len);
+        Ok(())
+    }
+
+    pub(crate) fn request_len(&self, function_code: u8) -> Option<RequestLen> {
+        Self::index(function_code).and_then(|index| self.0[index])
+    }
+}
+
 /// A single modbus RTU request.
 #[derive(Debug, PartialEq)]
 pub enum Request<'a, S: ArrayLength<u8>> {
@@ -161,6 +373,19 @@ pub enum Request<'a, S: ArrayLength<u8>> {
         address: u16,
         value: u16,
     },
+    ReadExceptionStatus,
+    Diagnostics {
+        sub_function: u16,
+        data: u16,
+    },
+    /// The diagnostics sub-function 0x00, which echoes query data of any length.
+    ///
+    /// Its frame ends with the idle line only, see `Modbus::on_idle_line`.
+    ReturnQueryData {
+        query: Pdu<'a, S>,
+    },
+    GetCommEventCounter,
+    GetCommEventLog,
     SetCoils {
         address: u16,
         count: u16,
@@ -171,4 +396,98 @@ pub enum Request<'a, S: ArrayLength<u8>> {
         count: u16,
         registers: RegisterStore<'a, S>,
     },
+    ReportServerId,
+    ReadFileRecord {
+        records: FileRecordStore<'a, S>,
+    },
+    WriteFileRecord {
+        records: FileRecordStore<'a, S>,
+    },
+    MaskWriteRegister {
+        address: u16,
+        and_mask: u16,
+        or_mask: u16,
+    },
+    ReadWriteMultipleRegisters {
+        read_address: u16,
+        read_count: u16,
+        write_address: u16,
+        write_count: u16,
+        registers: RegisterStore<'a, S>,
+    },
+    ReadFifoQueue {
+        pointer_address: u16,
+    },
+    ReadDeviceIdentification {
+        read_device_id_code: u8,
+        object_id: u8,
+    },
+    /// An encapsulated interface transport request of an MEI type other than read device identification.
+    ///
+    /// It is answered with an illegal function exception. Its frame ends with the idle line only.
+    EncapsulatedInterfaceTransport {
+        mei_type: u8,
+    },
+    /// Reads 32 bit registers of an Enron Modbus device.
+    ReadLongRegisters {
+        address: u16,
+        count: u16,
+    },
+    SetLongRegister {
+        address: u16,
+        value: u32,
+    },
+    SetLongRegisters {
+        address: u16,
+        count: u16,
+        registers: RegisterStore<'a, S>,
+    },
+    /// Reads the events of the Enron Modbus event log.
+    ReadEventLog,
+    /// Acknowledges the events of the Enron Modbus event log read last.
+    ///
+    /// `value` is the coil value written, which the response echoes.
+    AcknowledgeEventLog {
+        value: u16,
+    },
+    /// A request to a user-defined function registered with `Modbus::register_function`.
+    Custom {
+        pdu: Pdu<'a, S>,
+    },
+}
+
+impl<'a, S: ArrayLength<u8>> Request<'a, S> {
+    /// Returns the function code of the request.
+    pub fn function_code(&self) -> u8 {
+        match self {
+            Request::ReadCoil { .. } => consts::READ_COIL,
+            Request::ReadInput { .. } => consts::READ_INPUT,
+            Request::ReadOutputRegisters { .. } => consts::READ_OUTPUT_REGISTERS,
+            Request::ReadInputRegisters { .. } => consts::READ_INPUT_REGISTERS,
+            Request::SetCoil { .. } => consts::SET_COIL,
+            Request::SetRegister { .. } => consts::SET_REGISTER,
+            Request::ReadExceptionStatus => consts::READ_EXCEPTION_STATUS,
+            Request::Diagnostics { .. } | Request::ReturnQueryData { .. } => consts::DIAGNOSTICS,
+            Request::GetCommEventCounter => consts::GET_COMM_EVENT_COUNTER,
+            Request::GetCommEventLog => consts::GET_COMM_EVENT_LOG,
+            Request::SetCoils { .. } => consts::SET_COILS,
+            Request::SetRegisters { .. } => consts::SET_REGISTERS,
+            Request::ReportServerId => consts::REPORT_SERVER_ID,
+            Request::ReadFileRecord { .. } => consts::READ_FILE_RECORD,
+            Request::WriteFileRecord { .. } => consts::WRITE_FILE_RECORD,
+            Request::MaskWriteRegister { .. } => consts::MASK_WRITE_REGISTER,
+            Request::ReadWriteMultipleRegisters { .. } => consts::READ_WRITE_REGISTERS,
+            Request::ReadFifoQueue { .. } => consts::READ_FIFO_QUEUE,
+            Request::ReadDeviceIdentification { .. }
+            | Request::EncapsulatedInterfaceTransport { .. } => {
+                consts::ENCAPSULATED_INTERFACE_TRANSPORT
+            }
+            Request::ReadLongRegisters { .. } => consts::READ_OUTPUT_REGISTERS,
+            Request::SetLongRegister { .. } => consts::SET_REGISTER,
+            Request::SetLongRegisters { .. } => consts::SET_REGISTERS,
+            Request::ReadEventLog => consts::READ_OUTPUT_REGISTERS,
+            Request::AcknowledgeEventLog { .. } => consts::SET_COIL,
+            Request::Custom { pdu } => pdu.function_code(),
+        }
+    }
 }
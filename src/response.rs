@@ -0,0 +1,516 @@
+use crate::{
+    consts::{self, MAX_PDU_LEN},
+    data::{CoilState, FileRecord},
+    device_identification::{DeviceIdentification, DeviceObject, ServerId},
+    enron,
+    exception::Exception,
+    general,
+    handler::Handler,
+    request::{Request, RequestFrame},
+};
+use bbqueue::ArrayLength;
+
+/// The maximum number of coils or inputs which can be read with a single request.
+const MAX_READ_BITS: u16 = 0x07D0;
+/// The maximum number of registers which can be read with a single request.
+const MAX_READ_REGISTERS: u16 = 0x007D;
+/// The maximum number of coils which can be written with a single request.
+const MAX_WRITE_BITS: u16 = 0x07B0;
+/// The maximum number of registers which can be written with a single request.
+const MAX_WRITE_REGISTERS: u16 = 0x007B;
+/// The maximum number of registers which can be written with a single read/write multiple registers request.
+const MAX_READ_WRITE_REGISTERS: u16 = 0x0079;
+/// The maximum number of 32 bit registers which can be read with a single request.
+const MAX_READ_LONG_REGISTERS: u16 = MAX_READ_REGISTERS / 2;
+/// The maximum number of 32 bit registers which can be written with a single request.
+const MAX_WRITE_LONG_REGISTERS: u16 = MAX_WRITE_REGISTERS / 2;
+/// The maximum number of registers a FIFO queue may hold to be read.
+const MAX_FIFO_COUNT: u16 = 31;
+/// The only reference type valid for file records.
+const FILE_REFERENCE_TYPE: u8 = 6;
+/// The highest record number of a file.
+const MAX_RECORD_NUMBER: u32 = 0x270F;
+/// The maximum length of the object list in a read device identification response.
+/// It is the maximum PDU length minus the seven bytes in front of the list.
+const MAX_DEVICE_OBJECTS_LEN: usize = 246;
+
+/// Builds a single modbus RTU response frame in place.
+pub(crate) struct ResponseWriter<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> ResponseWriter<'b> {
+    pub(crate) fn new(buf: &'b mut [u8], slave_id: u8, function: u8) -> ResponseWriter<'b> {
+        buf[0] = slave_id;
+        buf[1] = function;
+        ResponseWriter { buf, len: 2 }
+    }
+
+    pub(crate) fn push(&mut self, byte: u8) {
+        self.buf[self.len] = byte;
+        self.len += 1;
+    }
+
+    pub(crate) fn push_u16(&mut self, value: u16) {
+        self.push_slice(&value.to_be_bytes());
+    }
+
+    pub(crate) fn push_slice(&mut self, data: &[u8]) {
+        self.buf[self.len..self.len + data.len()].copy_from_slice(data);
+        self.len += data.len();
+    }
+
+    /// Appends `len` bytes and returns them to be filled in place.
+    pub(crate) fn reserve(&mut self, len: usize) -> &mut [u8] {
+        self.len += len;
+        &mut self.buf[self.len - len..self.len]
+    }
+
+    /// Returns the space left for response data, leaving room for the CRC.
+    pub(crate) fn spare(&mut self) -> &mut [u8] {
+        let end = core::cmp::min(self.buf.len(), 1 + MAX_PDU_LEN + 2) - 2;
+        &mut self.buf[self.len..end]
+    }
+
+    /// Appends the CRC and returns the length of the complete frame.
+    pub(crate) fn finish(self) -> usize {
+        let crc = general::crc(&self.buf[..self.len]);
+        self.buf[self.len..self.len + 2].copy_from_slice(&crc.to_le_bytes());
+        self.len + 2
+    }
+
+    /// Discards everything written so far and finishes the frame as an exception response.
+    pub(crate) fn exception(mut self, exception: Exception) -> usize {
+        self.buf[1] |= 0x80;
+        self.len = 2;
+        self.push(exception as u8);
+        self.finish()
+    }
+}
+
+impl<'a, S: ArrayLength<u8>> RequestFrame<'a, S> {
+    /// Executes the request on the handler and writes the response frame into `buf`.
+    ///
+    /// Returns the length of the response or `None` if the request was a broadcast, which must not be answered.
+    /// `buf` has to hold at least 256 bytes, the maximum length of a modbus RTU frame.
+    pub fn respond<H: Handler>(&self, handler: &mut H, buf: &mut [u8]) -> Option<usize> {
+        let len = self.write_response(handler, buf);
+
+        if self.slave_id == 0 {
+            None
+        } else {
+            Some(len)
+        }
+    }
+
+    /// Executes the request on the handler and writes the response frame into `buf`, even for a broadcast.
+    pub(crate) fn write_response<H: Handler>(&self, handler: &mut H, buf: &mut [u8]) -> usize {
+        let mut response =
+            ResponseWriter::new(buf, self.slave_id as u8, self.request.function_code());
+        match execute(&self.request, handler, &mut response) {
+            Ok(()) => response.finish(),
+            Err(exception) => response.exception(exception),
+        }
+    }
+}
+
+/// Executes a single request and writes the data of its response.
+fn execute<S: ArrayLength<u8>, H: Handler>(
+    request: &Request<'_, S>,
+    handler: &mut H,
+    response: &mut ResponseWriter,
+) -> Result<(), Exception> {
+    match request {
+        Request::ReadCoil { address, count } => {
+            read_bits(response, *address, *count, |a| handler.read_coil(a))
+        }
+        Request::ReadInput { address, count } => {
+            read_bits(response, *address, *count, |a| handler.read_input(a))
+        }
+        Request::ReadOutputRegisters { address, count } => {
+            read_registers(response, *address, *count, |a| {
+                handler.read_output_register(a)
+            })
+        }
+        Request::ReadInputRegisters { address, count } => {
+            read_registers(response, *address, *count, |a| {
+                handler.read_input_register(a)
+            })
+        }
+        Request::SetCoil { address, status } => {
+            handler.set_coil(*address, *status)?;
+            response.push_u16(*address);
+            response.push_u16(*status as u16);
+            Ok(())
+        }
+        Request::SetRegister { address, value } => {
+            handler.set_register(*address, *value)?;
+            response.push_u16(*address);
+            response.push_u16(*value);
+            Ok(())
+        }
+        Request::ReadExceptionStatus => {
+            response.push(handler.read_exception_status()?);
+            Ok(())
+        }
+        Request::ReturnQueryData { query } => {
+            // The sub-function and query data follow the function code.
+            response.push_slice(&query.bytes()[1..]);
+            Ok(())
+        }
+        // Diagnostics are answered from the state of the receiver by `Modbus::serve`.
+        Request::Diagnostics { .. } | Request::GetCommEventCounter | Request::GetCommEventLog => {
+            Err(Exception::IllegalFunction)
+        }
+        Request::SetCoils {
+            address,
+            count,
+            coils,
+        } => {
+            check_range(*address, *count, MAX_WRITE_BITS)?;
+            if coils.byte_count() != (*count as usize).div_ceil(8) {
+                return Err(Exception::IllegalDataValue);
+            }
+            for (offset, status) in coils.iter().enumerate() {
+                handler.set_coil(*address + offset as u16, status)?;
+            }
+            response.push_u16(*address);
+            response.push_u16(*count);
+            Ok(())
+        }
+        Request::SetRegisters {
+            address,
+            count,
+            registers,
+        } => {
+            check_range(*address, *count, MAX_WRITE_REGISTERS)?;
+            if registers.len() != *count as usize {
+                return Err(Exception::IllegalDataValue);
+            }
+            for (offset, value) in registers.iter().enumerate() {
+                handler.set_register(*address + offset as u16, value)?;
+            }
+            response.push_u16(*address);
+            response.push_u16(*count);
+            Ok(())
+        }
+        Request::ReportServerId => {
+            let server_id = handler.server_id().ok_or(Exception::IllegalFunction)?;
+            if server_id.id.len() > ServerId::MAX_LEN {
+                return Err(Exception::ServerDeviceFailure);
+            }
+            response.push(server_id.id.len() as u8 + 1);
+            response.push_slice(server_id.id);
+            response.push(if server_id.running { 0xFF } else { 0x00 });
+            Ok(())
+        }
+        Request::ReadFileRecord { records } => {
+            let files = handler.file_store().ok_or(Exception::IllegalFunction)?;
+            let byte_count = records.bytes().len();
+            if !(0x07..=0xF5).contains(&byte_count) || byte_count % 7 != 0 {
+                return Err(Exception::IllegalDataValue);
+            }
+
+            // Validate all sub-requests before reading anything.
+            let mut len = 0;
+            for record in records.iter() {
+                check_file_record(&record)?;
+                len += 2 + 2 * record.record_length as usize;
+            }
+            if 2 + len > MAX_PDU_LEN {
+                return Err(Exception::IllegalDataValue);
+            }
+
+            response.push(len as u8);
+            for record in records.iter() {
+                let data_len = 2 * record.record_length as usize;
+                response.push(1 + data_len as u8);
+                response.push(FILE_REFERENCE_TYPE);
+                files.read_records(
+                    record.file_number,
+                    record.record_number,
+                    response.reserve(data_len),
+                )?;
+            }
+            Ok(())
+        }
+        Request::WriteFileRecord { records } => {
+            let files = handler.file_store().ok_or(Exception::IllegalFunction)?;
+            let byte_count = records.bytes().len();
+            if !(0x09..=0xFB).contains(&byte_count) {
+                return Err(Exception::IllegalDataValue);
+            }
+
+            // Validate all sub-requests before writing anything.
+            // The sub-requests also have to add up to the byte count exactly.
+            let mut len = 0;
+            for record in records.iter() {
+                check_file_record(&record)?;
+                len += 7 + record.data.len();
+            }
+            if len != byte_count {
+                return Err(Exception::IllegalDataValue);
+            }
+
+            for record in records.iter() {
+                files.write_records(record.file_number, record.record_number, record.data)?;
+            }
+
+            // The response is an echo of the request.
+            response.push(byte_count as u8);
+            response.push_slice(records.bytes());
+            Ok(())
+        }
+        Request::MaskWriteRegister {
+            address,
+            and_mask,
+            or_mask,
+        } => {
+            handler.mask_write_register(*address, *and_mask, *or_mask)?;
+            response.push_u16(*address);
+            response.push_u16(*and_mask);
+            response.push_u16(*or_mask);
+            Ok(())
+        }
+        Request::ReadWriteMultipleRegisters {
+            read_address,
+            read_count,
+            write_address,
+            write_count,
+            registers,
+        } => {
+            check_range(*read_address, *read_count, MAX_READ_REGISTERS)?;
+            check_range(*write_address, *write_count, MAX_READ_WRITE_REGISTERS)?;
+            if registers.len() != *write_count as usize {
+                return Err(Exception::IllegalDataValue);
+            }
+            // The write operation has to be performed before the read operation.
+            for (offset, value) in registers.iter().enumerate() {
+                handler.set_register(*write_address + offset as u16, value)?;
+            }
+            read_registers(response, *read_address, *read_count, |a| {
+                handler.read_output_register(a)
+            })
+        }
+        Request::ReadFifoQueue { pointer_address } => {
+            let fifo = handler.fifo_source().ok_or(Exception::IllegalFunction)?;
+            let count = fifo.fifo_count(*pointer_address)?;
+            if count > MAX_FIFO_COUNT {
+                return Err(Exception::IllegalDataValue);
+            }
+            response.push_u16(2 + 2 * count);
+            response.push_u16(count);
+            for index in 0..count {
+                response.push_u16(fifo.read_fifo(*pointer_address, index)?);
+            }
+            Ok(())
+        }
+        Request::ReadDeviceIdentification {
+            read_device_id_code,
+            object_id,
+        } => read_device_identification(
+            response,
+            handler.device_identification(),
+            *read_device_id_code,
+            *object_id,
+        ),
+        // Read device identification is the only MEI type supported.
+        Request::EncapsulatedInterfaceTransport { .. } => Err(Exception::IllegalFunction),
+        Request::ReadLongRegisters { address, count } => {
+            check_range(*address, *count, MAX_READ_LONG_REGISTERS)?;
+            response.push(*count as u8 * 4);
+            for offset in 0..*count {
+                response.push_slice(&handler.read_long_register(address + offset)?.to_be_bytes());
+            }
+            Ok(())
+        }
+        Request::SetLongRegister { address, value } => {
+            handler.set_long_register(*address, *value)?;
+            response.push_u16(*address);
+            response.push_slice(&value.to_be_bytes());
+            Ok(())
+        }
+        Request::SetLongRegisters {
+            address,
+            count,
+            registers,
+        } => {
+            check_range(*address, *count, MAX_WRITE_LONG_REGISTERS)?;
+            if registers.len() != 2 * *count as usize {
+                return Err(Exception::IllegalDataValue);
+            }
+            let mut registers = registers.iter();
+            for offset in 0..*count {
+                let high = registers.next().unwrap_or_default() as u32;
+                let low = registers.next().unwrap_or_default() as u32;
+                handler.set_long_register(address + offset, (high << 16) | low)?;
+            }
+            response.push_u16(*address);
+            response.push_u16(*count);
+            Ok(())
+        }
+        Request::ReadEventLog => {
+            let spare = response.spare();
+            let max_len = core::cmp::min(spare.len() - 1, 2 * MAX_READ_REGISTERS as usize);
+            let len = handler.read_event_log(&mut spare[1..1 + max_len])?;
+            if len > max_len {
+                return Err(Exception::ServerDeviceFailure);
+            }
+            spare[0] = len as u8;
+            response.reserve(1 + len);
+            Ok(())
+        }
+        Request::AcknowledgeEventLog { value } => {
+            handler.acknowledge_event_log()?;
+            response.push_u16(enron::EVENT_LOG_ADDRESS);
+            response.push_u16(*value);
+            Ok(())
+        }
+        Request::Custom { pdu } => {
+            let spare = response.spare();
+            let capacity = spare.len();
+            let len = handler.custom_function(pdu.bytes(), spare)?;
+            if len > capacity {
+                return Err(Exception::ServerDeviceFailure);
+            }
+            response.reserve(len);
+            Ok(())
+        }
+    }
+}
+
+/// Makes sure that `count` items starting at `address` are a valid range for a request.
+fn check_range(address: u16, count: u16, max: u16) -> Result<(), Exception> {
+    if count == 0 || count > max {
+        return Err(Exception::IllegalDataValue);
+    }
+    if address as u32 + count as u32 > 0x10000 {
+        return Err(Exception::IllegalDataAddress);
+    }
+    Ok(())
+}
+
+/// Makes sure that a file record sub-request addresses existing records.
+fn check_file_record(record: &FileRecord) -> Result<(), Exception> {
+    if record.reference_type != FILE_REFERENCE_TYPE
+        || record.file_number == 0
+        || record.record_number as u32 + record.record_length as u32 > MAX_RECORD_NUMBER + 1
+    {
+        return Err(Exception::IllegalDataAddress);
+    }
+    Ok(())
+}
+
+/// Writes the packed bits of a read coils or read inputs response.
+fn read_bits(
+    response: &mut ResponseWriter,
+    address: u16,
+    count: u16,
+    mut read: impl FnMut(u16) -> Result<CoilState, Exception>,
+) -> Result<(), Exception> {
+    check_range(address, count, MAX_READ_BITS)?;
+    response.push(count.div_ceil(8) as u8);
+    for offset in (0..count).step_by(8) {
+        let mut byte = 0;
+        for bit in 0..core::cmp::min(8, count - offset) {
+            if read(address + offset + bit)? == CoilState::On {
+                byte |= 1 << bit;
+            }
+        }
+        response.push(byte);
+    }
+    Ok(())
+}
+
+/// Writes the values of a read registers response.
+fn read_registers(
+    response: &mut ResponseWriter,
+    address: u16,
+    count: u16,
+    mut read: impl FnMut(u16) -> Result<u16, Exception>,
+) -> Result<(), Exception> {
+    check_range(address, count, MAX_READ_REGISTERS)?;
+    response.push(count as u8 * 2);
+    for offset in 0..count {
+        response.push_u16(read(address + offset)?);
+    }
+    Ok(())
+}
+
+/// Writes the objects of a read device identification response.
+///
+/// Stream access returns as many objects of the requested category as fit into a single response,
+/// starting at `object_id`. The master has to continue with the next object id if more objects follow.
+fn read_device_identification(
+    response: &mut ResponseWriter,
+    identification: Option<DeviceIdentification>,
+    read_device_id_code: u8,
+    object_id: u8,
+) -> Result<(), Exception> {
+    let identification = identification.ok_or(Exception::IllegalFunction)?;
+
+    // Determine the last object of the requested category.
+    let last = match read_device_id_code {
+        0x01 => 0x02,
+        0x02 => 0x7F,
+        0x03 | 0x04 => 0xFF,
+        _ => return Err(Exception::IllegalDataValue),
+    };
+
+    response.push(consts::READ_DEVICE_IDENTIFICATION);
+    response.push(read_device_id_code);
+    response.push(identification.conformity_level());
+
+    if read_device_id_code == 0x04 {
+        // Individual access to a single object.
+        let object = identification
+            .object(object_id)
+            .ok_or(Exception::IllegalDataAddress)?;
+        let value = object_value(object);
+        response.push_slice(&[0x00, 0x00, 0x01, object.id, value.len() as u8]);
+        response.push_slice(value);
+        return Ok(());
+    }
+
+    // If the requested object does not exist, the stream restarts at the beginning.
+    let first = match identification.object(object_id) {
+        Some(_) if object_id <= last => object_id,
+        _ => 0x00,
+    };
+    let objects = identification
+        .objects
+        .iter()
+        .filter(|object| object.id >= first && object.id <= last);
+
+    // Find out how many objects fit into this response and which object has to follow in the next one.
+    let mut count = 0;
+    let mut len = 0;
+    let mut next = None;
+    for object in objects.clone() {
+        len += 2 + object_value(object).len();
+        if len > MAX_DEVICE_OBJECTS_LEN {
+            next = Some(object.id);
+            break;
+        }
+        count += 1;
+    }
+
+    response.push(if next.is_some() { 0xFF } else { 0x00 });
+    response.push(next.unwrap_or(0x00));
+    response.push(count as u8);
+    for object in objects.take(count) {
+        let value = object_value(object);
+        response.push(object.id);
+        response.push(value.len() as u8);
+        response.push_slice(value);
+    }
+    Ok(())
+}
+
+/// Returns the value of a device object, truncated to fit into a single response.
+///
+/// Objects set up without `DeviceIdentification::new` can be longer, which would stall the stream access.
+fn object_value<'a>(object: &DeviceObject<'a>) -> &'a [u8] {
+    &object.value[..core::cmp::min(object.value.len(), DeviceObject::MAX_LEN)]
+}
@@ -0,0 +1,278 @@
+use embedded_hal::{digital::v2::OutputPin, serial, timer::CountDown};
+
+/// The delays around a transmission on a half duplex RS-485 bus.
+///
+/// The delays are given in the time unit of the timer, `None` skips a delay.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Turnaround<Time> {
+    /// The time between enabling the driver and sending the first byte.
+    pub pre_delay: Option<Time>,
+    /// The time between the transmission completing and disabling the driver.
+    pub post_delay: Option<Time>,
+    /// The minimum time between receiving a request and enabling the driver for the response.
+    pub response_delay: Option<Time>,
+}
+
+impl<Time> Default for Turnaround<Time> {
+    fn default() -> Turnaround<Time> {
+        Turnaround {
+            pre_delay: None,
+            post_delay: None,
+            response_delay: None,
+        }
+    }
+}
+
+/// An error during a transmission.
+#[derive(Debug, PartialEq)]
+pub enum TransmitError<S, P> {
+    /// The UART failed to send.
+    Serial(S),
+    /// The driver enable pin could not be switched.
+    Pin(P),
+}
+
+/// The transmit path of a half duplex RS-485 transceiver.
+///
+/// The driver is enabled through `driver_enable` for the duration of a transmission only,
+/// so the bus is released for the other nodes as soon as the last byte left the UART.
+/// For this to work the `flush` of the UART has to wait for the transmission to complete,
+/// not just for its transmit buffer to be empty.
+pub struct Rs485<U, P, T: CountDown> {
+    uart: U,
+    driver_enable: P,
+    timer: T,
+    turnaround: Turnaround<T::Time>,
+    /// Whether the response delay is running on the timer.
+    response_delay: bool,
+}
+
+impl<U, P, T> Rs485<U, P, T>
+where
+    U: serial::Write<u8>,
+    P: OutputPin,
+    T: CountDown,
+    T::Time: Clone,
+{
+    /// Creates the transmit path, the driver is expected to be disabled.
+    pub fn new(uart: U, driver_enable: P, timer: T, turnaround: Turnaround<T::Time>) -> Self {
+        Rs485 {
+            uart,
+            driver_enable,
+            timer,
+            turnaround,
+            response_delay: false,
+        }
+    }
+
+    /// Call this once a complete request was received.
+    ///
+    /// It starts the response delay the next transmission waits for.
+    pub fn on_request_received(&mut self) {
+        if let Some(delay) = self.turnaround.response_delay.clone() {
+            self.timer.start(delay);
+            self.response_delay = true;
+        }
+    }
+
+    /// Sends a frame, blocking until it was transmitted completely and the driver is disabled again.
+    ///
+    /// The driver is disabled even if the transmission failed, as the bus would be blocked otherwise.
+    pub fn transmit(&mut self, frame: &[u8]) -> Result<(), TransmitError<U::Error, P::Error>> {
+        if self.response_delay {
+            // The timer cannot fail.
+            let _ = nb::block!(self.timer.wait());
+            self.response_delay = false;
+        }
+
+        self.driver_enable.set_high().map_err(TransmitError::Pin)?;
+        let sent = self.send(frame);
+        let released = self.driver_enable.set_low().map_err(TransmitError::Pin);
+        sent.and(released)
+    }
+
+    /// Returns the UART, the driver enable pin and the timer.
+    pub fn free(self) -> (U, P, T) {
+        (self.uart, self.driver_enable, self.timer)
+    }
+
+    fn send(&mut self, frame: &[u8]) -> Result<(), TransmitError<U::Error, P::Error>> {
+        self.delay(self.turnaround.pre_delay.clone());
+        for byte in frame {
+            nb::block!(self.uart.write(*byte)).map_err(TransmitError::Serial)?;
+        }
+        nb::block!(self.uart.flush()).map_err(TransmitError::Serial)?;
+        self.delay(self.turnaround.post_delay.clone());
+        Ok(())
+    }
+
+    fn delay(&mut self, delay: Option<T::Time>) {
+        if let Some(delay) = delay {
+            self.timer.start(delay);
+            // The timer cannot fail.
+            let _ = nb::block!(self.timer.wait());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rs485, TransmitError, Turnaround};
+    use embedded_hal::{digital::v2::OutputPin, serial, timer::CountDown};
+    use std::{cell::RefCell, rc::Rc};
+    use void::Void;
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        DriverEnable(bool),
+        Write(u8),
+        Flush,
+        Delay(u32),
+    }
+
+    type Log = Rc<RefCell<Vec<Event>>>;
+
+    /// A UART which reports transmission complete one poll after the flush was started.
+    struct Uart {
+        log: Log,
+        flushing: bool,
+        fail: bool,
+    }
+
+    impl serial::Write<u8> for Uart {
+        type Error = ();
+
+        fn write(&mut self, word: u8) -> nb::Result<(), ()> {
+            if self.fail {
+                return Err(nb::Error::Other(()));
+            }
+            self.log.borrow_mut().push(Event::Write(word));
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), ()> {
+            if !self.flushing {
+                self.flushing = true;
+                return Err(nb::Error::WouldBlock);
+            }
+            self.flushing = false;
+            self.log.borrow_mut().push(Event::Flush);
+            Ok(())
+        }
+    }
+
+    struct Pin(Log);
+
+    impl OutputPin for Pin {
+        type Error = ();
+
+        fn set_low(&mut self) -> Result<(), ()> {
+            self.0.borrow_mut().push(Event::DriverEnable(false));
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), ()> {
+            self.0.borrow_mut().push(Event::DriverEnable(true));
+            Ok(())
+        }
+    }
+
+    /// A timer counting in microseconds which logs each expired delay.
+    struct Timer {
+        log: Log,
+        running: Option<u32>,
+    }
+
+    impl CountDown for Timer {
+        type Time = u32;
+
+        fn start<T: Into<u32>>(&mut self, count: T) {
+            self.running = Some(count.into());
+        }
+
+        fn wait(&mut self) -> nb::Result<(), Void> {
+            if let Some(delay) = self.running.take() {
+                self.log.borrow_mut().push(Event::Delay(delay));
+            }
+            Ok(())
+        }
+    }
+
+    fn rs485(log: &Log, turnaround: Turnaround<u32>, fail: bool) -> Rs485<Uart, Pin, Timer> {
+        let uart = Uart {
+            log: log.clone(),
+            flushing: false,
+            fail,
+        };
+        let timer = Timer {
+            log: log.clone(),
+            running: None,
+        };
+        Rs485::new(uart, Pin(log.clone()), timer, turnaround)
+    }
+
+    #[test]
+    fn transmit() {
+        let log = Log::default();
+        let mut rs485 = rs485(
+            &log,
+            Turnaround {
+                pre_delay: Some(10),
+                post_delay: Some(20),
+                response_delay: Some(1750),
+            },
+            false,
+        );
+
+        rs485.on_request_received();
+        rs485.transmit(&[0x11, 0x03]).unwrap();
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                Event::Delay(1750),
+                Event::DriverEnable(true),
+                Event::Delay(10),
+                Event::Write(0x11),
+                Event::Write(0x03),
+                Event::Flush,
+                Event::Delay(20),
+                Event::DriverEnable(false),
+            ]
+        );
+
+        // Without a new request there is no response delay.
+        log.borrow_mut().clear();
+        rs485.transmit(&[0x11]).unwrap();
+        assert_eq!(log.borrow()[0], Event::DriverEnable(true));
+    }
+
+    #[test]
+    fn transmit_without_delays() {
+        let log = Log::default();
+        let mut rs485 = rs485(&log, Turnaround::default(), false);
+
+        rs485.on_request_received();
+        rs485.transmit(&[0x11]).unwrap();
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                Event::DriverEnable(true),
+                Event::Write(0x11),
+                Event::Flush,
+                Event::DriverEnable(false),
+            ]
+        );
+    }
+
+    #[test]
+    fn release_on_error() {
+        let log = Log::default();
+        let mut rs485 = rs485(&log, Turnaround::default(), true);
+
+        assert_eq!(rs485.transmit(&[0x11]), Err(TransmitError::Serial(())));
+        assert_eq!(
+            *log.borrow(),
+            vec![Event::DriverEnable(true), Event::DriverEnable(false)]
+        );
+    }
+}
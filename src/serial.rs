@@ -0,0 +1,224 @@
+use crate::modbus::Modbus;
+use bbqueue::ArrayLength;
+use embedded_hal::serial::{Read, Write};
+
+/// The number of received bytes collected before they are handed to the receiver.
+const CHUNK_LEN: usize = 32;
+
+/// Connects a UART to the RTU receiver and transmitter.
+///
+/// Received bytes are collected and handed to `Modbus::on_data_received` in chunks,
+/// which is a lot cheaper than handing over every single byte.
+/// The last chunk of a frame is handed over once the line is idle.
+pub struct SerialAdapter<U> {
+    uart: U,
+    received: [u8; CHUNK_LEN],
+    len: usize,
+    /// Whether the bytes until the next idle line are dropped after a receive error.
+    discard: bool,
+}
+
+impl<U> SerialAdapter<U> {
+    pub fn new(uart: U) -> SerialAdapter<U> {
+        SerialAdapter {
+            uart,
+            received: [0; CHUNK_LEN],
+            len: 0,
+            discard: false,
+        }
+    }
+
+    /// Call this when the line went idle, either from an idle line interrupt
+    /// or from a timer expiring 3.5 characters after the last received byte.
+    pub fn on_idle<S: ArrayLength<u8>>(&mut self, modbus: &mut Modbus<'_, S>) {
+        if self.len > 0 {
+            modbus.on_data_received(&self.received[..self.len]);
+            self.len = 0;
+        }
+        self.discard = false;
+        modbus.on_idle_line();
+    }
+
+    /// Returns the UART.
+    pub fn free(self) -> U {
+        self.uart
+    }
+}
+
+impl<U: Read<u8>> SerialAdapter<U> {
+    /// Reads all bytes the UART has available. Call this in the data received interrupt.
+    ///
+    /// A receive error, like a framing error or an overrun, drops the frame being received,
+    /// see `Modbus::on_receive_error`, and is returned. The following bytes are dropped
+    /// until the line went idle, the next call continues with the next frame.
+    pub fn on_receive<S: ArrayLength<u8>>(
+        &mut self,
+        modbus: &mut Modbus<'_, S>,
+    ) -> Result<(), U::Error> {
+        loop {
+            match self.uart.read() {
+                Ok(_) if self.discard => {}
+                Ok(byte) => {
+                    self.received[self.len] = byte;
+                    self.len += 1;
+                    if self.len == CHUNK_LEN {
+                        modbus.on_data_received(&self.received);
+                        self.len = 0;
+                    }
+                }
+                Err(nb::Error::WouldBlock) => return Ok(()),
+                Err(nb::Error::Other(e)) => {
+                    self.len = 0;
+                    self.discard = true;
+                    modbus.on_receive_error();
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+impl<U: Write<u8>> SerialAdapter<U> {
+    /// Sends a frame, e.g. a response written by `Modbus::serve`, blocking until it was transmitted.
+    pub fn transmit<S: ArrayLength<u8>>(
+        &mut self,
+        modbus: &mut Modbus<'_, S>,
+        frame: &[u8],
+    ) -> Result<(), U::Error> {
+        modbus.on_data_transmitted(frame);
+        for byte in frame {
+            nb::block!(self.uart.write(*byte))?;
+        }
+        nb::block!(self.uart.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SerialAdapter;
+    use crate::{Exception, Handler, Modbus};
+    use bbqueue::{atomic::consts::U2048, BBBuffer};
+    use embedded_hal::serial::{Read, Write};
+    use std::collections::VecDeque;
+
+    /// A UART which loops every transmitted byte back like some RS-485 transceivers do.
+    #[derive(Default)]
+    struct Uart {
+        rx: VecDeque<Result<u8, ()>>,
+        tx: Vec<u8>,
+    }
+
+    impl Read<u8> for Uart {
+        type Error = ();
+
+        fn read(&mut self) -> nb::Result<u8, ()> {
+            match self.rx.pop_front() {
+                Some(Ok(byte)) => Ok(byte),
+                Some(Err(())) => Err(nb::Error::Other(())),
+                None => Err(nb::Error::WouldBlock),
+            }
+        }
+    }
+
+    impl Write<u8> for Uart {
+        type Error = ();
+
+        fn write(&mut self, word: u8) -> nb::Result<(), ()> {
+            self.tx.push(word);
+            self.rx.push_back(Ok(word));
+            Ok(())
+        }
+
+        fn flush(&mut self) -> nb::Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    struct Register(u16);
+
+    impl Handler for Register {
+        fn read_output_register(&mut self, address: u16) -> Result<u16, Exception> {
+            match address {
+                0 => Ok(self.0),
+                _ => Err(Exception::IllegalDataAddress),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn serve() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = Modbus::new(&bb);
+        modbus.set_echo_suppression(true);
+        let mut serial = SerialAdapter::new(Uart::default());
+        let mut buf = [0; 256];
+
+        // Read holding register 0.
+        let request = [0x11, 0x03, 0x00, 0x00, 0x00, 0x01, 0x86, 0x9A];
+        for _ in 0..2 {
+            serial.uart.rx.extend(request.iter().map(|&byte| Ok(byte)));
+            serial.on_receive(&mut modbus).unwrap();
+            serial.on_idle(&mut modbus);
+
+            let len = modbus
+                .serve(&mut Register(42), &mut buf)
+                .await
+                .unwrap()
+                .unwrap();
+            serial.transmit(&mut modbus, &buf[..len]).unwrap();
+            assert_eq!(
+                serial.uart.tx,
+                vec![0x11, 0x03, 0x02, 0x00, 0x2A, 0xF8, 0x58]
+            );
+
+            // The echo of the response is received but suppressed.
+            serial.on_receive(&mut modbus).unwrap();
+            serial.on_idle(&mut modbus);
+            serial.uart.tx.clear();
+        }
+    }
+
+    #[tokio::test]
+    async fn receive_error() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = Modbus::new(&bb);
+        let mut serial = SerialAdapter::new(Uart::default());
+        let mut buf = [0; 256];
+
+        // The frame with the error is dropped up to the idle line.
+        serial.uart.rx.extend(vec![Ok(0x11), Err(()), Ok(0x03)]);
+        assert_eq!(serial.on_receive(&mut modbus), Err(()));
+        assert_eq!(serial.len, 0);
+        serial.on_receive(&mut modbus).unwrap();
+        assert_eq!(serial.len, 0);
+        serial.on_idle(&mut modbus);
+        assert_eq!(modbus.counters().bus_character_overrun, 1);
+
+        // The next frame is received again.
+        let request = [0x11, 0x03, 0x00, 0x00, 0x00, 0x01, 0x86, 0x9A];
+        serial.uart.rx.extend(request.iter().map(|&byte| Ok(byte)));
+        serial.on_receive(&mut modbus).unwrap();
+        serial.on_idle(&mut modbus);
+        let len = modbus
+            .serve(&mut Register(42), &mut buf)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..len], &[0x11, 0x03, 0x02, 0x00, 0x2A, 0xF8, 0x58]);
+        assert_eq!(modbus.counters().bus_communication_error, 0);
+    }
+
+    #[test]
+    fn chunks() {
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = Modbus::new(&bb);
+        let mut serial = SerialAdapter::new(Uart::default());
+
+        // A full chunk is handed over right away, the rest waits for the idle line.
+        serial.uart.rx.extend((0..40).map(Ok));
+        serial.on_receive(&mut modbus).unwrap();
+        assert_eq!(serial.len, 8);
+        serial.on_idle(&mut modbus);
+        assert_eq!(serial.len, 0);
+    }
+}
@@ -0,0 +1,159 @@
+/// The timing of the characters on a serial line in microseconds.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct BusTiming {
+    /// The duration of a single character.
+    pub character: u32,
+    /// The maximum silent interval between two characters of a frame.
+    pub t1_5: u32,
+    /// The minimum silent interval between two frames.
+    pub t3_5: u32,
+}
+
+impl BusTiming {
+    /// Returns the timing of a line at `baud_rate` with characters of `character_bits`,
+    /// which is 11 for modbus RTU including the start, parity and stop bits.
+    ///
+    /// Above 19200 baud, the modbus specification fixes t1.5 at 750µs and t3.5 at 1750µs.
+    /// The baud rate must not be 0.
+    pub fn new(baud_rate: u32, character_bits: u32) -> BusTiming {
+        debug_assert!(baud_rate > 0, "baud rate of 0");
+        let (t1_5, t3_5) = if baud_rate > 19200 {
+            (750, 1750)
+        } else {
+            (
+                3_000_000 * character_bits / (2 * baud_rate),
+                7_000_000 * character_bits / (2 * baud_rate),
+            )
+        };
+        BusTiming {
+            character: 1_000_000 * character_bits / baud_rate,
+            t1_5,
+            t3_5,
+        }
+    }
+}
+
+/// Statistics on the silent intervals on the bus in microseconds.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct TimingStats {
+    /// The longest silent interval between two characters of a frame.
+    pub max_character_gap: u32,
+    /// The number of silent intervals longer than t1.5 but shorter than t3.5.
+    ///
+    /// The modbus specification declares the frame such an interval occurs in as incomplete.
+    pub t1_5_violations: u32,
+    /// The shortest silent interval between two frames,
+    /// e.g. the latency of the master between receiving a response and sending the next request.
+    pub min_frame_gap: Option<u32>,
+    /// The longest silent interval between two frames.
+    pub max_frame_gap: Option<u32>,
+}
+
+/// Measures the silent intervals from the timestamps of the received data.
+pub(crate) struct Timing {
+    bus: BusTiming,
+    /// The time the last character was received at.
+    last: Option<u32>,
+    pub(crate) stats: TimingStats,
+}
+
+impl Timing {
+    pub(crate) fn new(bus: BusTiming) -> Timing {
+        Timing {
+            bus,
+            last: None,
+            stats: TimingStats::default(),
+        }
+    }
+
+    /// Measures the silent interval before `len` characters, the last of which was received at `timestamp`.
+    ///
+    /// Timestamps are in microseconds and may wrap around.
+    /// Returns whether the interval is longer than t1.5 but shorter than t3.5, which is illegal within a frame.
+    pub(crate) fn received(&mut self, len: usize, timestamp: u32) -> bool {
+        if len == 0 {
+            return false;
+        }
+        let last = match self.last.replace(timestamp) {
+            Some(last) => last,
+            None => return false,
+        };
+
+        // The characters were received back to back, if not the silence is in front of them.
+        let gap = timestamp
+            .wrapping_sub(last)
+            .saturating_sub(len as u32 * self.bus.character);
+        let stats = &mut self.stats;
+        if gap >= self.bus.t3_5 {
+            stats.min_frame_gap = Some(stats.min_frame_gap.map_or(gap, |min| min.min(gap)));
+            stats.max_frame_gap = Some(stats.max_frame_gap.map_or(gap, |max| max.max(gap)));
+            return false;
+        }
+
+        stats.max_character_gap = stats.max_character_gap.max(gap);
+        if gap > self.bus.t1_5 {
+            stats.t1_5_violations += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BusTiming, Timing, TimingStats};
+
+    #[test]
+    fn bus_timing() {
+        assert_eq!(
+            BusTiming::new(9600, 11),
+            BusTiming {
+                character: 1145,
+                t1_5: 1718,
+                t3_5: 4010,
+            }
+        );
+        assert_eq!(
+            BusTiming::new(115_200, 11),
+            BusTiming {
+                character: 95,
+                t1_5: 750,
+                t3_5: 1750,
+            }
+        );
+    }
+
+    #[test]
+    fn gaps() {
+        let mut timing = Timing::new(BusTiming::new(9600, 11));
+
+        // A request in two chunks, the second one following after a short gap.
+        assert!(!timing.received(4, 10_000));
+        assert!(!timing.received(4, 10_000 + 4 * 1145 + 500));
+        // A gap of two characters within a frame.
+        assert!(timing.received(2, 10_000 + 6 * 1145 + 500 + 2290));
+
+        assert_eq!(
+            timing.stats,
+            TimingStats {
+                max_character_gap: 2290,
+                t1_5_violations: 1,
+                min_frame_gap: None,
+                max_frame_gap: None,
+            }
+        );
+    }
+
+    #[test]
+    fn frame_gap() {
+        let mut timing = Timing::new(BusTiming::new(9600, 11));
+
+        // The response follows the request after a frame gap, with a timer which wrapped around.
+        assert!(!timing.received(8, u32::MAX - 999));
+        assert!(!timing.received(7, 7 * 1145 + 4000));
+        assert_eq!(timing.stats.min_frame_gap, Some(5000));
+        assert_eq!(timing.stats.max_frame_gap, Some(5000));
+        assert_eq!(timing.stats.t1_5_violations, 0);
+    }
+}
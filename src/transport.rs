@@ -0,0 +1,377 @@
+use crate::{consts::MAX_FRAME_LEN, error::Error, handler::Handler, modbus::Modbus};
+use bbqueue::ArrayLength;
+use core::{
+    convert::Infallible,
+    future::Future,
+    task::{Context, Poll},
+};
+
+/// The number of bytes read from the stream at once.
+const CHUNK_LEN: usize = 64;
+
+/// An error which ends an async transport.
+#[derive(Debug, PartialEq)]
+pub enum TransportError<R, W> {
+    /// Reading from the stream failed.
+    Read(R),
+    /// Writing a response to the stream failed.
+    Write(W),
+    /// The stream was closed.
+    Closed,
+}
+
+/// Polls `Modbus::serve` once, returning `None` if no complete request was received yet.
+///
+/// Dropping the pending future is fine as the receiver keeps its state between polls.
+fn poll_serve<S: ArrayLength<u8>, H: Handler>(
+    modbus: &mut Modbus<'_, S>,
+    handler: &mut H,
+    buf: &mut [u8],
+) -> Option<Result<Option<usize>, Error>> {
+    let serve = modbus.serve(handler, buf);
+    futures::pin_mut!(serve);
+    match serve.poll(&mut Context::from_waker(futures::task::noop_waker_ref())) {
+        Poll::Ready(result) => Some(result),
+        Poll::Pending => None,
+    }
+}
+
+/// The stream a transport runs a slave on.
+trait Stream {
+    type ReadError;
+    type WriteError;
+
+    /// Reads the next bytes, or returns `None` once no byte was received for the idle timeout.
+    async fn read(&mut self, buf: &mut [u8]) -> Result<Option<usize>, Self::ReadError>;
+
+    /// Writes a response and waits until it was flushed.
+    async fn write(&mut self, frame: &[u8]) -> Result<(), Self::WriteError>;
+}
+
+/// Serves requests received from `stream` until it fails or is closed.
+async fn run<T: Stream, S: ArrayLength<u8>, H: Handler>(
+    stream: &mut T,
+    modbus: &mut Modbus<'_, S>,
+    handler: &mut H,
+) -> Result<Infallible, TransportError<T::ReadError, T::WriteError>> {
+    let mut chunk = [0; CHUNK_LEN];
+    let mut buf = [0; MAX_FRAME_LEN];
+    loop {
+        match stream
+            .read(&mut chunk)
+            .await
+            .map_err(TransportError::Read)?
+        {
+            Some(0) => return Err(TransportError::Closed),
+            Some(len) => modbus.on_data_received(&chunk[..len]),
+            // The line is idle, so an incomplete frame is dropped.
+            None => modbus.on_idle_line(),
+        }
+
+        while let Some(result) = poll_serve(modbus, handler, &mut buf) {
+            if let Ok(Some(len)) = result {
+                modbus.on_data_transmitted(&buf[..len]);
+                stream
+                    .write(&buf[..len])
+                    .await
+                    .map_err(TransportError::Write)?;
+            }
+        }
+    }
+}
+
+/// Runs a slave on a pair of `tokio::io` streams, e.g. the halves of a serial port or a pty.
+#[cfg(feature = "tokio")]
+pub struct TokioTransport<R, W> {
+    reader: R,
+    writer: W,
+    idle_timeout: core::time::Duration,
+}
+
+#[cfg(feature = "tokio")]
+impl<R, W> TokioTransport<R, W>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    /// Creates a transport which considers the line idle once nothing was read for `idle_timeout`.
+    ///
+    /// The timeout should be at least t3.5, plus the latency of the driver of the serial port.
+    pub fn new(reader: R, writer: W, idle_timeout: core::time::Duration) -> TokioTransport<R, W> {
+        TokioTransport {
+            reader,
+            writer,
+            idle_timeout,
+        }
+    }
+
+    /// Serves requests until the stream fails or is closed, so it only ever returns an error.
+    ///
+    /// Frames which cannot be received, e.g. because of a CRC error or because they were cut short
+    /// by an idle line, are skipped. They are counted in the diagnostic counters of the receiver.
+    pub async fn run<S: ArrayLength<u8>, H: Handler>(
+        &mut self,
+        modbus: &mut Modbus<'_, S>,
+        handler: &mut H,
+    ) -> Result<Infallible, TransportError<tokio::io::Error, tokio::io::Error>> {
+        run(self, modbus, handler).await
+    }
+
+    /// Returns the reader and the writer.
+    pub fn free(self) -> (R, W) {
+        (self.reader, self.writer)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<R, W> Stream for TokioTransport<R, W>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    type ReadError = tokio::io::Error;
+    type WriteError = tokio::io::Error;
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<Option<usize>, tokio::io::Error> {
+        use tokio::io::AsyncReadExt;
+
+        match tokio::time::timeout(self.idle_timeout, self.reader.read(buf)).await {
+            Ok(result) => result.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    async fn write(&mut self, frame: &[u8]) -> Result<(), tokio::io::Error> {
+        use tokio::io::AsyncWriteExt;
+
+        self.writer.write_all(frame).await?;
+        self.writer.flush().await
+    }
+}
+
+/// Runs a slave on a pair of `embedded-io-async` streams, e.g. the halves of an embassy UART.
+#[cfg(feature = "embedded-io-async")]
+pub struct EmbeddedIoTransport<R, W, T> {
+    reader: R,
+    writer: W,
+    idle: T,
+}
+
+#[cfg(feature = "embedded-io-async")]
+impl<R, W, T, F> EmbeddedIoTransport<R, W, T>
+where
+    R: embedded_io_async::Read,
+    W: embedded_io_async::Write,
+    T: FnMut() -> F,
+    F: Future<Output = ()>,
+{
+    /// Creates a transport which considers the line idle once the future returned by `idle` completes
+    /// before anything was read, e.g. `|| Timer::after_micros(1750)` with embassy.
+    ///
+    /// A read is dropped when the line went idle, so the reader has to be cancel safe.
+    pub fn new(reader: R, writer: W, idle: T) -> EmbeddedIoTransport<R, W, T> {
+        EmbeddedIoTransport {
+            reader,
+            writer,
+            idle,
+        }
+    }
+
+    /// Serves requests until the stream fails or is closed, so it only ever returns an error.
+    ///
+    /// Frames which cannot be received are skipped like with `TokioTransport::run`.
+    pub async fn run<S: ArrayLength<u8>, H: Handler>(
+        &mut self,
+        modbus: &mut Modbus<'_, S>,
+        handler: &mut H,
+    ) -> Result<Infallible, TransportError<R::Error, W::Error>> {
+        run(self, modbus, handler).await
+    }
+
+    /// Returns the reader and the writer.
+    pub fn free(self) -> (R, W) {
+        (self.reader, self.writer)
+    }
+}
+
+#[cfg(feature = "embedded-io-async")]
+impl<R, W, T, F> Stream for EmbeddedIoTransport<R, W, T>
+where
+    R: embedded_io_async::Read,
+    W: embedded_io_async::Write,
+    T: FnMut() -> F,
+    F: Future<Output = ()>,
+{
+    type ReadError = R::Error;
+    type WriteError = W::Error;
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<Option<usize>, R::Error> {
+        use futures::future::{select, Either};
+
+        let read = self.reader.read(buf);
+        let idle = (self.idle)();
+        futures::pin_mut!(read, idle);
+        match select(read, idle).await {
+            Either::Left((result, _)) => result.map(Some),
+            Either::Right(((), _)) => Ok(None),
+        }
+    }
+
+    async fn write(&mut self, frame: &[u8]) -> Result<(), W::Error> {
+        self.writer.write_all(frame).await?;
+        self.writer.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Exception, Handler};
+    use std::collections::VecDeque;
+
+    /// Noise with an unknown function code, a valid request for holding register 0,
+    /// the same request cut short and one with a broken CRC, each followed by an idle line,
+    /// and a final valid request.
+    fn script() -> Script {
+        Script {
+            chunks: vec![
+                Some(&[0x11, 0x30][..]),
+                None,
+                Some(&REQUEST[..]),
+                None,
+                Some(&REQUEST[..5]),
+                None,
+                Some(&[0x11, 0x03, 0x00, 0x00, 0x00, 0x01, 0x86, 0x9B][..]),
+                None,
+                Some(&REQUEST[..]),
+            ]
+            .into_iter()
+            .collect(),
+            #[cfg(feature = "tokio")]
+            idle: None,
+        }
+    }
+
+    const REQUEST: [u8; 8] = [0x11, 0x03, 0x00, 0x00, 0x00, 0x01, 0x86, 0x9A];
+    const RESPONSE: [u8; 7] = [0x11, 0x03, 0x02, 0x00, 0x2A, 0xF8, 0x58];
+
+    /// A reader handing out chunks, where `None` stands for an idle line which blocks a single read.
+    struct Script {
+        chunks: VecDeque<Option<&'static [u8]>>,
+        /// The timer of the current idle line.
+        #[cfg(feature = "tokio")]
+        idle: Option<tokio::time::Delay>,
+    }
+
+    impl Script {
+        /// Returns the length of the next chunk, or `None` for an idle line.
+        fn next(&mut self, buf: &mut [u8]) -> Option<usize> {
+            match self.chunks.pop_front() {
+                Some(Some(chunk)) => {
+                    buf[..chunk.len()].copy_from_slice(chunk);
+                    Some(chunk.len())
+                }
+                Some(None) => None,
+                // The stream is closed.
+                None => Some(0),
+            }
+        }
+    }
+
+    struct Register(u16);
+
+    impl Handler for Register {
+        fn read_output_register(&mut self, address: u16) -> Result<u16, Exception> {
+            match address {
+                0 => Ok(self.0),
+                _ => Err(Exception::IllegalDataAddress),
+            }
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    impl tokio::io::AsyncRead for Script {
+        fn poll_read(
+            mut self: core::pin::Pin<&mut Self>,
+            cx: &mut core::task::Context<'_>,
+            buf: &mut [u8],
+        ) -> core::task::Poll<std::io::Result<usize>> {
+            use core::future::Future;
+
+            // An idle line lasts longer than the idle timeout, which ends the read.
+            if let Some(None) = self.chunks.front() {
+                let idle = self.idle.get_or_insert_with(|| {
+                    tokio::time::delay_for(std::time::Duration::from_millis(20))
+                });
+                if core::pin::Pin::new(idle).poll(cx).is_pending() {
+                    return core::task::Poll::Pending;
+                }
+                self.idle = None;
+                self.chunks.pop_front();
+            }
+            match self.next(buf) {
+                Some(len) => core::task::Poll::Ready(Ok(len)),
+                None => core::task::Poll::Ready(Err(std::io::Error::other(
+                    "idle lines scripted back to back",
+                ))),
+            }
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn tokio_transport() {
+        use crate::{Modbus, TokioTransport, TransportError};
+        use bbqueue::{atomic::consts::U2048, BBBuffer};
+        use core::time::Duration;
+
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = Modbus::new(&bb);
+        let mut transport = TokioTransport::new(script(), Vec::new(), Duration::from_millis(2));
+
+        let result = transport.run(&mut modbus, &mut Register(42)).await;
+        assert!(matches!(result, Err(TransportError::Closed)));
+        let (_, written) = transport.free();
+        assert_eq!(written, [RESPONSE, RESPONSE].concat());
+        // Only the broken CRC counts as a communication error.
+        assert_eq!(modbus.counters().bus_message, 5);
+        assert_eq!(modbus.counters().bus_communication_error, 1);
+    }
+
+    #[cfg(feature = "embedded-io-async")]
+    impl embedded_io_async::ErrorType for Script {
+        type Error = core::convert::Infallible;
+    }
+
+    #[cfg(feature = "embedded-io-async")]
+    impl embedded_io_async::Read for Script {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            match self.next(buf) {
+                Some(len) => Ok(len),
+                // The idle timer ends the read.
+                None => core::future::pending().await,
+            }
+        }
+    }
+
+    #[cfg(feature = "embedded-io-async")]
+    #[tokio::test]
+    async fn embedded_io_transport() {
+        use crate::{EmbeddedIoTransport, Modbus, TransportError};
+        use bbqueue::{atomic::consts::U2048, BBBuffer};
+
+        let bb = BBBuffer::<U2048>::new();
+        let mut modbus = Modbus::new(&bb);
+        let mut written = [0; 32];
+        {
+            // The idle timer expires as soon as a read blocks.
+            let mut transport =
+                EmbeddedIoTransport::new(script(), &mut written[..], || futures::future::ready(()));
+            let result = transport.run(&mut modbus, &mut Register(42)).await;
+            assert_eq!(result, Err(TransportError::Closed));
+        }
+        assert_eq!(&written[..14], &[RESPONSE, RESPONSE].concat()[..]);
+        // Only the broken CRC counts as a communication error.
+        assert_eq!(modbus.counters().bus_message, 5);
+        assert_eq!(modbus.counters().bus_communication_error, 1);
+    }
+}